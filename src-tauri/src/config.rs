@@ -0,0 +1,226 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+const CONFIG_PATH: &str = "./config.json";
+
+/// Largest payload a single UDP datagram can carry.
+const MAX_UDP_PAYLOAD: usize = 65507;
+
+lazy_static! {
+    pub(crate) static ref CONFIG: RwLock<AppConfig> = RwLock::new(AppConfig::load(CONFIG_PATH));
+}
+
+/// Settings read from `config.json`, missing keys fall back to the defaults.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct AppConfig {
+    pub bind_address: IpAddr,
+    pub server_port: u16,
+    pub client_port: u16,
+    /// Destination of `Target::Broadcast`, either a broadcast or a multicast group address.
+    pub broadcast_address: IpAddr,
+    pub client_timeout_secs: u64,
+    pub live_check_interval_secs: u64,
+    pub recv_buffer_size: usize,
+    pub history_dir: PathBuf,
+    /// Every saved session is also copied here when set.
+    pub backup_dir: Option<PathBuf>,
+    /// Sessions kept per user in `backup_dir`, older copies are deleted.
+    pub backup_keep: usize,
+    /// JSON file of the trainee roster, read once at startup.
+    pub roster_path: PathBuf,
+    /// JSON file of the PDF report template, the default template is used while it's missing.
+    pub report_template_path: PathBuf,
+    /// TrueType font of reports whose template names none, e.g. one that can show Chinese names.
+    pub report_font_path: Option<PathBuf>,
+    /// SQLite index of every stored session, read once at startup.
+    pub database_path: PathBuf,
+    /// JSON file of the known headsets and their names, read once at startup.
+    pub device_registry_path: PathBuf,
+    pub command_retry: RetryPolicy,
+}
+
+/// How often and how patiently an unacknowledged command is resent.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_timeout_ms: u64,
+    pub backoff_factor: f64,
+    pub max_timeout_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 4,
+            initial_timeout_ms: 500,
+            backoff_factor: 2.0,
+            max_timeout_ms: 4000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn initial_timeout(&self) -> Duration {
+        Duration::from_millis(self.initial_timeout_ms)
+    }
+
+    /// The wait after `timeout` expired, grown by `backoff_factor` up to `max_timeout_ms`.
+    pub fn next_timeout(&self, timeout: Duration) -> Duration {
+        timeout
+            .mul_f64(self.backoff_factor)
+            .min(Duration::from_millis(self.max_timeout_ms))
+    }
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            server_port: 3333,
+            client_port: 3334,
+            broadcast_address: IpAddr::V4(Ipv4Addr::BROADCAST),
+            client_timeout_secs: 5,
+            live_check_interval_secs: 3,
+            recv_buffer_size: 8192,
+            history_dir: PathBuf::from("./play_history"),
+            backup_dir: None,
+            backup_keep: 20,
+            roster_path: PathBuf::from("./roster.json"),
+            report_template_path: PathBuf::from("./report_template.json"),
+            report_font_path: None,
+            database_path: PathBuf::from("./play_history.db"),
+            device_registry_path: PathBuf::from("./devices.json"),
+            command_retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl AppConfig {
+    /// Reads the config file, using defaults if it is missing or invalid.
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref();
+        let config = match std::fs::read_to_string(path) {
+            Ok(json) => match serde_json::from_str::<AppConfig>(&json) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Error parsing config {}: {}", path.display(), e);
+                    return Self::default();
+                }
+            },
+            Err(_) => return Self::default(),
+        };
+
+        match config.validate() {
+            Ok(()) => config,
+            Err(e) => {
+                eprintln!("Invalid config {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.client_timeout_secs == 0 {
+            return Err("client_timeout_secs must be greater than 0".into());
+        }
+        if self.live_check_interval_secs == 0 {
+            return Err("live_check_interval_secs must be greater than 0".into());
+        }
+        if !(64..=MAX_UDP_PAYLOAD).contains(&self.recv_buffer_size) {
+            return Err(format!(
+                "recv_buffer_size must be between 64 and {}",
+                MAX_UDP_PAYLOAD
+            ));
+        }
+        if self.broadcast_address.is_unspecified() {
+            return Err("broadcast_address must not be unspecified".into());
+        }
+        if self.history_dir.as_os_str().is_empty() {
+            return Err("history_dir must not be empty".into());
+        }
+        if let Some(backup_dir) = &self.backup_dir {
+            if backup_dir.as_os_str().is_empty() || *backup_dir == self.history_dir {
+                return Err("backup_dir must be a directory other than history_dir".into());
+            }
+            if self.backup_keep == 0 {
+                return Err("backup_keep must be greater than 0".into());
+            }
+        }
+        if self.roster_path.as_os_str().is_empty() {
+            return Err("roster_path must not be empty".into());
+        }
+        if self.report_template_path.as_os_str().is_empty() {
+            return Err("report_template_path must not be empty".into());
+        }
+        if self
+            .report_font_path
+            .as_ref()
+            .is_some_and(|path| path.as_os_str().is_empty())
+        {
+            return Err("report_font_path must not be empty when set".into());
+        }
+        if self.database_path.as_os_str().is_empty() {
+            return Err("database_path must not be empty".into());
+        }
+        if self.device_registry_path.as_os_str().is_empty() {
+            return Err("device_registry_path must not be empty".into());
+        }
+
+        let retry = &self.command_retry;
+        if retry.max_attempts == 0 {
+            return Err("command_retry.max_attempts must be greater than 0".into());
+        }
+        if retry.initial_timeout_ms == 0 || retry.max_timeout_ms < retry.initial_timeout_ms {
+            return Err(
+                "command_retry timeouts must be positive with max_timeout_ms >= initial_timeout_ms"
+                    .into(),
+            );
+        }
+        if !(retry.backoff_factor >= 1.0 && retry.backoff_factor.is_finite()) {
+            return Err("command_retry.backoff_factor must be at least 1".into());
+        }
+        Ok(())
+    }
+
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_address, self.server_port)
+    }
+
+    pub fn broadcast_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.broadcast_address, self.client_port)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+
+    pub fn live_check_interval(&self) -> Duration {
+        Duration::from_secs(self.live_check_interval_secs)
+    }
+}
+
+pub async fn current() -> AppConfig {
+    CONFIG.read().await.clone()
+}
+
+/// Validates and persists a new config; network settings apply on the next `start_udp`.
+pub async fn update(config: AppConfig) -> Result<AppConfig, String> {
+    config.validate()?;
+
+    let json = serde_json::to_string_pretty(&config).map_err(|e| e.to_string())?;
+    tokio::fs::write(CONFIG_PATH, json)
+        .await
+        .map_err(|e| format!("Error writing config {}: {}", CONFIG_PATH, e))?;
+
+    *CONFIG.write().await = config.clone();
+    Ok(config)
+}
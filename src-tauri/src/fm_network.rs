@@ -1,64 +1,375 @@
 pub mod action;
+mod chunks;
 pub mod client;
+pub mod command;
+pub mod error;
 pub mod handler;
+pub mod history_decoder;
 pub mod jpeg_decoder;
 pub mod packet;
+pub mod registry;
+pub mod reliable;
+pub mod stream_stats;
+pub mod subscription;
+pub mod target;
 
-use std::{collections::HashMap, net::SocketAddr, ops::Deref, sync::Arc};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::Arc,
+};
 
-use lazy_static::lazy_static;
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, mpsc, Mutex, RwLock};
 
+use crate::config;
 use crate::fm_network::{
-    action::FMAction, client::ClientStatus, handler::SocketHandler, jpeg_decoder::JPEGDecoder,
+    action::{ClientChangedDetail, FMAction},
+    client::ClientStatus,
+    error::NetworkError,
+    handler::{HistoryReply, HistoryRequestOutcome, NetworkStatus, ReceivedHistory, SocketHandler},
+    history_decoder::HistoryDecoder,
+    jpeg_decoder::JPEGDecoder,
     packet::FMPacket,
+    registry::{DeviceRecord, DeviceRegistry, DeviceUpdate},
+    reliable::{CommandOutcome, PendingCommands},
+    stream_stats::{StreamStats, StreamStatsTracker},
+    subscription::{ActionFilter, Subscription},
+    target::{Delivery, Target},
 };
 
-const FM_SERVER_PORT: u16 = 3333;
-const FM_CLIENT_PORT: u16 = 3334;
-
-pub(crate) const PLAY_HISTORY_PATH: &str = "./play_history";
+/// Events a subscriber may fall behind by before it starts missing them.
+const EVENT_BUFFER: usize = 256;
 
-lazy_static! {
-    static ref CLIENTS: RwLock<HashMap<SocketAddr, ClientStatus>> = RwLock::new(HashMap::new());
-    static ref SOCKET_HANDLER: RwLock<SocketHandler> = RwLock::new(SocketHandler::new());
-    static ref LISTENERS: RwLock<Vec<Arc<Listener>>> = RwLock::new(Vec::new());
-    static ref JPEG_DECODERS: RwLock<HashMap<SocketAddr, JPEGDecoder>> =
-        RwLock::new(HashMap::new());
+/// The headset network service, cheap to clone and held in Tauri managed state.
+#[derive(Clone)]
+pub struct FmNetwork {
+    shared: Arc<Shared>,
 }
 
-struct Listener {
-    callback: Arc<dyn Fn(&FMAction) + Send + Sync>,
+/// State shared between [`FmNetwork`] and the tasks spawned by its [`SocketHandler`].
+pub(crate) struct Shared {
+    handler: RwLock<SocketHandler>,
+    clients: RwLock<HashMap<SocketAddr, ClientStatus>>,
+    events: broadcast::Sender<FMAction>,
+    jpeg_decoders: RwLock<HashMap<SocketAddr, JPEGDecoder>>,
+    history_decoders: RwLock<HashMap<SocketAddr, HistoryDecoder>>,
+    stream_stats: RwLock<HashMap<SocketAddr, StreamStatsTracker>>,
+    registry: RwLock<DeviceRegistry>,
+    commands: Mutex<PendingCommands>,
+    /// Pull requests waiting for histories, told whether each history received got stored.
+    history_requests: Mutex<HashMap<IpAddr, mpsc::UnboundedSender<HistoryReply>>>,
+    /// Every received history, unlike `events` never dropped when the consumer falls behind.
+    histories: mpsc::UnboundedSender<ReceivedHistory>,
+    history_receiver: Mutex<Option<mpsc::UnboundedReceiver<ReceivedHistory>>>,
 }
 
-pub async fn listen<F>(callback: F)
-where
-    F: Fn(&FMAction) + Send + Sync + 'static,
-{
-    let mut writer = LISTENERS.write().await;
-    writer.push(Arc::new(Listener {
-        callback: Arc::new(callback),
-    }));
-}
+impl FmNetwork {
+    /// Creates the service with the device registry stored at `registry_path`.
+    pub fn new<P: AsRef<Path>>(registry_path: P) -> Self {
+        let (histories, history_receiver) = mpsc::unbounded_channel();
+        Self {
+            shared: Arc::new(Shared {
+                handler: RwLock::new(SocketHandler::new()),
+                clients: RwLock::new(HashMap::new()),
+                events: broadcast::channel(EVENT_BUFFER).0,
+                jpeg_decoders: RwLock::new(HashMap::new()),
+                history_decoders: RwLock::new(HashMap::new()),
+                stream_stats: RwLock::new(HashMap::new()),
+                registry: RwLock::new(DeviceRegistry::load(registry_path)),
+                commands: Mutex::new(PendingCommands::new()),
+                history_requests: Mutex::new(HashMap::new()),
+                histories,
+                history_receiver: Mutex::new(Some(history_receiver)),
+            }),
+        }
+    }
+
+    /// Calls `callback` for every action passing `filter` until the returned handle is dropped.
+    pub fn listen<F>(&self, filter: ActionFilter, callback: F) -> Subscription
+    where
+        F: Fn(&FMAction) + Send + Sync + 'static,
+    {
+        Subscription::spawn(self.shared.events.subscribe(), filter, callback)
+    }
+
+    /// Hands out the queue of received histories, to the one consumer storing them.
+    ///
+    /// Histories queue up until it is taken, `None` once it was.
+    pub async fn take_histories(&self) -> Option<mpsc::UnboundedReceiver<ReceivedHistory>> {
+        self.shared.history_receiver.lock().await.take()
+    }
+
+    pub async fn start(&self) -> Result<SocketAddr, NetworkError> {
+        let mut handler = self.shared.handler.write().await;
+
+        handler.run(self.shared.clone()).await
+    }
+
+    /// Closes the socket and reports every connected client as removed, listeners are kept.
+    pub async fn stop(&self) {
+        self.shared.handler.write().await.stop();
+
+        let removed: Vec<SocketAddr> = self
+            .shared
+            .clients
+            .write()
+            .await
+            .drain()
+            .map(|(addr, _)| addr)
+            .collect();
+        for addr in removed {
+            self.shared
+                .emit_action(FMAction::ClientChanged(ClientChangedDetail::removed(addr)));
+        }
+
+        self.shared.jpeg_decoders.write().await.clear();
+        self.shared.history_decoders.write().await.clear();
+        self.shared.stream_stats.write().await.clear();
+        self.shared.commands.lock().await.clear();
+        self.shared.history_requests.lock().await.clear();
+    }
+
+    /// Stops and starts again, picking up config changes.
+    pub async fn restart(&self) -> Result<SocketAddr, NetworkError> {
+        self.stop().await;
+        self.start().await
+    }
+
+    pub async fn status(&self) -> NetworkStatus {
+        self.shared.handler.read().await.status()
+    }
+
+    pub async fn stream_stats(&self) -> Vec<StreamStats> {
+        self.shared.stream_stats().await
+    }
+
+    pub async fn list_devices(&self) -> Vec<DeviceRecord> {
+        self.shared.registry.read().await.list()
+    }
+
+    /// Applies `update` to a known device, fields it leaves out are kept.
+    pub async fn update_device(
+        &self,
+        device_id: &str,
+        update: DeviceUpdate,
+    ) -> Option<DeviceRecord> {
+        let mut registry = self.shared.registry.write().await;
+        let record = registry.update(device_id, update)?;
+        registry.save().await;
+        Some(record)
+    }
+
+    /// Removes a device from the registry, connected clients keep their address but lose the identity.
+    pub async fn forget_device(&self, device_id: &str) -> bool {
+        let mut registry = self.shared.registry.write().await;
+        if !registry.forget(device_id) {
+            return false;
+        }
+        registry.save().await;
+        drop(registry);
+
+        for client in self.shared.clients.write().await.values_mut() {
+            if client.device_id.as_deref() == Some(device_id) {
+                client.device_id = None;
+            }
+        }
+        true
+    }
+
+    pub async fn send(&self, addr: Addr, packet: FMPacket) -> Result<(), NetworkError> {
+        self.shared.send(addr, packet).await
+    }
 
-pub async fn run() -> bool {
-    let mut handler = SOCKET_HANDLER.write().await;
+    /// Sends `data` as a command and resolves once the headset acks it or retries run out.
+    pub async fn send_reliable(&self, addr: Addr, data: String) -> CommandOutcome {
+        let addr = match SocketAddr::try_from(addr) {
+            Ok(addr) => addr,
+            Err(e) => {
+                return CommandOutcome::Failed {
+                    reason: e.to_string(),
+                }
+            }
+        };
+        let policy = config::current().await.command_retry;
+
+        reliable::send_reliable(&self.shared, addr, data, &policy).await
+    }
+
+    /// Pulls the stored play histories from the headset at `addr`, saved like pushed ones.
+    ///
+    /// Only resolves as received once the consumer of [`Self::take_histories`] stored them.
+    pub async fn request_history(&self, addr: Addr) -> HistoryRequestOutcome {
+        let addr = match SocketAddr::try_from(addr) {
+            Ok(addr) => addr,
+            Err(e) => {
+                return HistoryRequestOutcome::Failed {
+                    reason: e.to_string(),
+                }
+            }
+        };
+        let policy = config::current().await.command_retry;
+
+        handler::request_history(&self.shared, addr, &policy).await
+    }
+
+    /// Sends `data` to every headset selected by `target`, as acked commands if `reliable`.
+    ///
+    /// Reliable sends run concurrently; the report keeps the order of the recipients.
+    pub async fn send_to(
+        &self,
+        target: Target,
+        data: String,
+        reliable: bool,
+    ) -> Result<Vec<Delivery>, NetworkError> {
+        let handler = self.shared.handler.read().await;
+        if !handler.is_running() {
+            return Err(NetworkError::NotRunning);
+        }
+        if let Target::Broadcast = target {
+            if reliable {
+                return Err(NetworkError::ReliableBroadcast);
+            }
+            let addr = handler.broadcast(FMPacket::StringPacket { data }).await?;
+            return Ok(vec![Delivery::new(
+                addr.to_string(),
+                None,
+                CommandOutcome::Sent,
+            )]);
+        }
+        drop(handler);
+
+        let mut report = Vec::new();
+        let mut pending = Vec::new();
+        for (label, addr) in self.shared.recipients(target).await {
+            let addr = match addr {
+                Ok(addr) => addr,
+                Err(e) => {
+                    let reason = e.to_string();
+                    report.push(Delivery::new(
+                        label,
+                        None,
+                        CommandOutcome::Failed { reason },
+                    ));
+                    continue;
+                }
+            };
+            let device_name = self.shared.device_name(addr).await;
 
-    handler.run().await
+            if reliable {
+                let network = self.clone();
+                let data = data.clone();
+                let task =
+                    tokio::spawn(async move { network.send_reliable(addr.into(), data).await });
+                pending.push((report.len(), task));
+                report.push(Delivery::new(label, device_name, CommandOutcome::Sent));
+            } else {
+                let packet = FMPacket::StringPacket { data: data.clone() };
+                let outcome = match self.send(addr.into(), packet).await {
+                    Ok(()) => CommandOutcome::Sent,
+                    Err(e) => CommandOutcome::Failed {
+                        reason: e.to_string(),
+                    },
+                };
+                report.push(Delivery::new(label, device_name, outcome));
+            }
+        }
+
+        for (index, task) in pending {
+            report[index].outcome = task.await.unwrap_or_else(|e| CommandOutcome::Failed {
+                reason: e.to_string(),
+            });
+        }
+        Ok(report)
+    }
 }
 
-pub async fn stop() {
-    let mut handler = SOCKET_HANDLER.write().await;
-    handler.stop();
+impl Shared {
+    pub(crate) async fn send(&self, addr: Addr, packet: FMPacket) -> Result<(), NetworkError> {
+        let addr = SocketAddr::try_from(addr)?;
+        self.handler.read().await.send(addr, packet).await
+    }
 
-    let mut clients = CLIENTS.write().await;
-    clients.clear();
+    pub(crate) async fn stream_stats(&self) -> Vec<StreamStats> {
+        let mut stats = self.stream_stats.write().await;
+        stats
+            .values_mut()
+            .map(|tracker| tracker.snapshot())
+            .collect()
+    }
+
+    /// Resolves `target` to addresses, keeping entries that failed to parse with their error.
+    async fn recipients(&self, target: Target) -> Vec<(String, Result<SocketAddr, NetworkError>)> {
+        let client_timeout = config::current().await.client_timeout();
+        let clients = self.clients.read().await;
+
+        let mut addrs: Vec<SocketAddr> = match target {
+            Target::All => clients
+                .values()
+                .filter(|client| client.is_active(client_timeout))
+                .map(|client| client.address)
+                .collect(),
+            Target::Group(group) => {
+                let registry = self.registry.read().await;
+                clients
+                    .values()
+                    .filter(|client| client.is_active(client_timeout))
+                    .filter(|client| {
+                        client
+                            .device_id
+                            .as_deref()
+                            .and_then(|id| registry.get(id))
+                            .is_some_and(|record| record.group.as_deref() == Some(group.as_str()))
+                    })
+                    .map(|client| client.address)
+                    .collect()
+            }
+            Target::Addresses(addrs) => {
+                return addrs
+                    .into_iter()
+                    .map(|addr| (addr.clone(), SocketAddr::try_from(Addr::from(addr))))
+                    .collect()
+            }
+            Target::Broadcast => Vec::new(),
+        };
 
-    let mut listeners = LISTENERS.write().await;
-    listeners.clear();
+        addrs.sort();
+        addrs
+            .into_iter()
+            .map(|addr| (addr.ip().to_string(), Ok(addr)))
+            .collect()
+    }
+
+    /// The registry name of the connected headset at `addr`, if it said hello.
+    ///
+    /// Port `0`, an address given without a port, matches only if one client uses that ip.
+    async fn device_name(&self, addr: SocketAddr) -> Option<String> {
+        let device_id = {
+            let clients = self.clients.read().await;
+            let client = match clients.get(&addr) {
+                Some(client) => client,
+                None if addr.port() == 0 => {
+                    let mut same_ip = clients
+                        .values()
+                        .filter(|client| client.address.ip() == addr.ip());
+                    match (same_ip.next(), same_ip.next()) {
+                        (Some(client), None) => client,
+                        _ => return None,
+                    }
+                }
+                None => return None,
+            };
+            client.device_id.clone()?
+        };
+
+        let registry = self.registry.read().await;
+        registry.get(&device_id).map(|record| record.name.clone())
+    }
 
-    let mut decoders = JPEG_DECODERS.write().await;
-    decoders.clear();
+    /// Publishes `action` without waiting on subscribers, dropped if nobody listens.
+    pub(crate) fn emit_action(&self, action: FMAction) {
+        let _ = self.events.send(action);
+    }
 }
 
 pub enum Addr {
@@ -66,16 +377,18 @@ pub enum Addr {
     SocketAddr(SocketAddr),
 }
 
-impl Into<SocketAddr> for Addr {
-    fn into(self) -> SocketAddr {
-        match self {
-            Addr::String(ip) => {
-                let addr: SocketAddr = format!("{}:{}", ip, FM_CLIENT_PORT)
-                    .parse()
-                    .expect("Invalid IP address");
-                addr
-            }
-            Addr::SocketAddr(addr) => addr,
+impl TryFrom<Addr> for SocketAddr {
+    type Error = NetworkError;
+
+    fn try_from(addr: Addr) -> Result<Self, Self::Error> {
+        match addr {
+            // the port is replaced by the configured client port when sending, it only
+            // tells apart headsets behind the same ip
+            Addr::String(ip) => ip
+                .parse::<SocketAddr>()
+                .or_else(|_| ip.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 0)))
+                .map_err(|_| NetworkError::InvalidAddress(ip)),
+            Addr::SocketAddr(addr) => Ok(addr),
         }
     }
 }
@@ -91,13 +404,3 @@ impl From<SocketAddr> for Addr {
         Addr::SocketAddr(addr)
     }
 }
-
-pub async fn send(addr: Addr, packet: FMPacket) {
-    SOCKET_HANDLER.read().await.send(addr.into(), packet).await;
-}
-
-pub(crate) async fn emit_action<'a>(action: FMAction<'a>) {
-    for listener in LISTENERS.read().await.iter() {
-        listener.callback.deref()(&action);
-    }
-}
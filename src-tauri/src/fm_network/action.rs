@@ -1,36 +1,110 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc};
 
-use serde::Serialize;
-use serde_json::Value;
+use serde::{Deserialize, Serialize};
 
-use crate::fm_network::packet::FMPacket;
+use crate::fm_network::{packet::FMPacket, registry::DeviceRecord, stream_stats::StreamStats};
+use crate::play_history::PlayHistory;
 
-pub enum FMAction<'a> {
+/// An event published on the network bus, cheap to clone for every subscriber.
+#[derive(Clone, Debug)]
+pub enum FMAction {
     ClientChanged(ClientChangedDetail),
-    JpegDecoded(JpegDecodedDetail<'a>),
+    JpegDecoded(JpegDecodedDetail),
     PacketReceived {
         addr: SocketAddr,
         packet: Arc<FMPacket>,
     },
-    HistoryReceived(HistoryDetail<'a>),
+    HistoryReceived(HistoryDetail),
+    HistoryTransferFailed(HistoryTransferFailedDetail),
+    MalformedPacket(MalformedPacketDetail),
+    StreamStats(Vec<StreamStats>),
+    DeviceIdentified(DeviceIdentifiedDetail),
+    /// Delivered only to a subscriber that fell behind and missed `skipped` events.
+    Lagged {
+        skipped: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ActionKind {
+    ClientChanged,
+    JpegDecoded,
+    PacketReceived,
+    HistoryReceived,
+    HistoryTransferFailed,
+    MalformedPacket,
+    StreamStats,
+    DeviceIdentified,
+    Lagged,
 }
 
-#[derive(Serialize, Debug)]
+impl FMAction {
+    pub fn kind(&self) -> ActionKind {
+        match self {
+            Self::ClientChanged(_) => ActionKind::ClientChanged,
+            Self::JpegDecoded(_) => ActionKind::JpegDecoded,
+            Self::PacketReceived { .. } => ActionKind::PacketReceived,
+            Self::HistoryReceived(_) => ActionKind::HistoryReceived,
+            Self::HistoryTransferFailed(_) => ActionKind::HistoryTransferFailed,
+            Self::MalformedPacket(_) => ActionKind::MalformedPacket,
+            Self::StreamStats(_) => ActionKind::StreamStats,
+            Self::DeviceIdentified(_) => ActionKind::DeviceIdentified,
+            Self::Lagged { .. } => ActionKind::Lagged,
+        }
+    }
+
+    /// The client the action is about, `None` for actions covering every client.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            Self::ClientChanged(detail) => detail.add.or(detail.remove),
+            Self::JpegDecoded(detail) => Some(detail.addr),
+            Self::PacketReceived { addr, .. } => Some(*addr),
+            Self::HistoryReceived(detail) => Some(detail.addr),
+            Self::HistoryTransferFailed(detail) => Some(detail.addr),
+            Self::MalformedPacket(detail) => Some(detail.addr),
+            Self::StreamStats(_) => None,
+            Self::DeviceIdentified(detail) => Some(detail.addr),
+            Self::Lagged { .. } => None,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
 pub(crate) struct ClientChangedDetail {
     add: Option<SocketAddr>,
     remove: Option<SocketAddr>,
 }
 
-#[derive(Serialize, Debug)]
-pub(crate) struct JpegDecodedDetail<'a> {
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct JpegDecodedDetail {
+    addr: SocketAddr,
+    data: Arc<Vec<u8>>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct HistoryDetail {
+    pub(crate) addr: SocketAddr,
+    pub(crate) history: PlayHistory,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct HistoryTransferFailedDetail {
     addr: SocketAddr,
-    data: &'a Vec<u8>,
+    transfer_id: i32,
+    reason: String,
 }
 
-#[derive(Serialize, Debug)]
-pub(crate) struct HistoryDetail<'a> {
-    pub(crate) player_id: &'a str,
-    pub(crate) map: HashMap<String, Value>,
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct MalformedPacketDetail {
+    addr: SocketAddr,
+    reason: String,
+    total: u64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub(crate) struct DeviceIdentifiedDetail {
+    addr: SocketAddr,
+    device: DeviceRecord,
 }
 
 impl ClientChangedDetail {
@@ -46,14 +120,40 @@ impl ClientChangedDetail {
     }
 }
 
-impl<'a> JpegDecodedDetail<'a> {
-    pub fn new(addr: SocketAddr, data: &'a Vec<u8>) -> Self {
+impl JpegDecodedDetail {
+    pub fn new(addr: SocketAddr, data: Arc<Vec<u8>>) -> Self {
         Self { addr, data }
     }
 }
 
-impl<'a> HistoryDetail<'a> {
-    pub fn new(player_id: &'a str, map: HashMap<String, Value>) -> Self {
-        Self { player_id, map }
+impl HistoryDetail {
+    pub fn new(addr: SocketAddr, history: PlayHistory) -> Self {
+        Self { addr, history }
+    }
+}
+
+impl HistoryTransferFailedDetail {
+    pub fn new(addr: SocketAddr, transfer_id: i32, reason: String) -> Self {
+        Self {
+            addr,
+            transfer_id,
+            reason,
+        }
+    }
+}
+
+impl MalformedPacketDetail {
+    pub fn new(addr: SocketAddr, reason: String, total: u64) -> Self {
+        Self {
+            addr,
+            reason,
+            total,
+        }
+    }
+}
+
+impl DeviceIdentifiedDetail {
+    pub fn new(addr: SocketAddr, device: DeviceRecord) -> Self {
+        Self { addr, device }
     }
 }
@@ -0,0 +1,130 @@
+use std::{
+    collections::BTreeMap,
+    time::{Duration, Instant},
+};
+
+/// Collects the chunks of one payload sent as `(offset, bytes)` pieces in any order.
+pub(crate) struct ChunkAssembler {
+    data: Vec<u8>,
+    /// Received byte ranges, `start -> end`, merged so they never overlap.
+    ranges: BTreeMap<usize, usize>,
+    byte_received: usize,
+    started_at: Instant,
+}
+
+impl ChunkAssembler {
+    pub fn new(length: usize) -> Self {
+        Self {
+            data: vec![0; length],
+            ranges: BTreeMap::new(),
+            byte_received: 0,
+            started_at: Instant::now(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn received(&self) -> usize {
+        self.byte_received
+    }
+
+    pub fn started_at(&self) -> Instant {
+        self.started_at
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started_at.elapsed()
+    }
+
+    /// Copies a chunk into the payload, returns `false` if it carried no new bytes.
+    ///
+    /// The caller checks that the chunk fits inside the payload.
+    pub fn insert(&mut self, offset: usize, data: &[u8]) -> bool {
+        let mut start = offset;
+        let mut end = offset + data.len();
+
+        let overlapping: Vec<(usize, usize)> = self
+            .ranges
+            .range(..=end)
+            .filter(|(_, range_end)| **range_end >= start)
+            .map(|(s, e)| (*s, *e))
+            .collect();
+
+        let mut already_covered = 0;
+        for (range_start, range_end) in overlapping {
+            already_covered += range_end.min(end).saturating_sub(range_start.max(start));
+            start = start.min(range_start);
+            end = end.max(range_end);
+            self.ranges.remove(&range_start);
+        }
+        self.ranges.insert(start, end);
+
+        let new_bytes = data.len() - already_covered;
+        if new_bytes == 0 {
+            return false;
+        }
+
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        self.byte_received += new_bytes;
+        true
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.byte_received >= self.data.len()
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completes_with_chunks_in_any_order() {
+        let mut chunks = ChunkAssembler::new(6);
+        assert!(chunks.insert(4, b"ef"));
+        assert!(chunks.insert(0, b"ab"));
+        assert!(!chunks.is_complete());
+        assert!(chunks.insert(2, b"cd"));
+
+        assert!(chunks.is_complete());
+        assert_eq!(chunks.received(), 6);
+        assert_eq!(chunks.into_data(), b"abcdef");
+    }
+
+    #[test]
+    fn duplicate_chunk_adds_nothing() {
+        let mut chunks = ChunkAssembler::new(4);
+        assert!(chunks.insert(0, b"ab"));
+        assert!(!chunks.insert(0, b"ab"));
+        assert_eq!(chunks.received(), 2);
+        assert!(!chunks.is_complete());
+    }
+
+    #[test]
+    fn overlapping_chunks_count_only_new_bytes() {
+        let mut chunks = ChunkAssembler::new(10);
+        assert!(chunks.insert(0, b"abcd"));
+        assert!(chunks.insert(6, b"ghij"));
+        // covers the end of the first range, the gap and the start of the second
+        assert!(chunks.insert(2, b"cdefgh"));
+        assert_eq!(chunks.received(), 10);
+        assert!(chunks.is_complete());
+        assert_eq!(chunks.into_data(), b"abcdefghij");
+    }
+
+    #[test]
+    fn chunk_inside_a_received_range_adds_nothing() {
+        let mut chunks = ChunkAssembler::new(8);
+        assert!(chunks.insert(0, b"abcdef"));
+        assert!(!chunks.insert(2, b"cd"));
+        assert!(chunks.insert(4, b"efgh"));
+        assert_eq!(chunks.received(), 8);
+        assert!(chunks.is_complete());
+    }
+}
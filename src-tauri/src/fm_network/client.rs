@@ -4,6 +4,9 @@ use std::net::SocketAddr;
 pub struct ClientStatus {
     pub address: SocketAddr,
     pub last_heartbeat: std::time::Instant,
+    pub malformed_packets: u64,
+    /// Set once the headset introduced itself with a hello packet.
+    pub device_id: Option<String>,
 }
 
 impl ClientStatus {
@@ -11,6 +14,8 @@ impl ClientStatus {
         Self {
             address,
             last_heartbeat: std::time::Instant::now(),
+            malformed_packets: 0,
+            device_id: None,
         }
     }
 
@@ -18,8 +23,8 @@ impl ClientStatus {
         self.last_heartbeat = std::time::Instant::now();
     }
 
-    pub fn is_active(&self) -> bool {
-        self.last_heartbeat.elapsed().as_secs() < 5
+    pub fn is_active(&self, timeout: std::time::Duration) -> bool {
+        self.last_heartbeat.elapsed() < timeout
     }
 }
 
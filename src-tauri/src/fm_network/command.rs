@@ -0,0 +1,160 @@
+use serde::{Deserialize, Serialize};
+
+use crate::fm_network::error::NetworkError;
+
+/// Control commands the VR client understands, sent as the text of a string or command packet.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(
+    tag = "command",
+    content = "args",
+    rename_all = "snake_case",
+    deny_unknown_fields
+)]
+pub enum HeadsetCommand {
+    StartMission {
+        mission: String,
+    },
+    Stop,
+    Pause,
+    ChangeLanguage {
+        language: String,
+    },
+    Recenter,
+    /// Asks the headset to export its play history.
+    RequestHistory,
+    SetStreamQuality {
+        quality: StreamQuality,
+    },
+}
+
+/// Downscale of the mirrored view, the number in the client's `screenN` command.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamQuality {
+    Full,
+    Half,
+    Thumbnail,
+}
+
+impl HeadsetCommand {
+    /// Rejects arguments the client would silently ignore.
+    pub fn validate(&self) -> Result<(), NetworkError> {
+        let (name, value) = match self {
+            Self::StartMission { mission } => ("mission", mission),
+            Self::ChangeLanguage { language } => ("language", language),
+            _ => return Ok(()),
+        };
+
+        if value.trim().is_empty() || value.contains(char::is_whitespace) {
+            return Err(NetworkError::InvalidCommand(format!(
+                "{} must be a single non-empty word, got {:?}",
+                name, value
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn to_wire(&self) -> String {
+        match self {
+            Self::StartMission { mission } => format!("start {}", mission),
+            Self::Stop => "stop".into(),
+            Self::Pause => "pause".into(),
+            Self::ChangeLanguage { language } => format!("language {}", language),
+            Self::Recenter => "recenter".into(),
+            Self::RequestHistory => "export".into(),
+            Self::SetStreamQuality { quality } => format!("screen{}", quality.scale()),
+        }
+    }
+}
+
+impl StreamQuality {
+    fn scale(self) -> u8 {
+        match self {
+            Self::Full => 1,
+            Self::Half => 2,
+            Self::Thumbnail => 5,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(json: &str) -> Result<HeadsetCommand, serde_json::Error> {
+        serde_json::from_str(json)
+    }
+
+    #[test]
+    fn parses_commands_with_and_without_args() {
+        assert_eq!(
+            parse(r#"{"command":"stop"}"#).unwrap(),
+            HeadsetCommand::Stop
+        );
+        assert_eq!(
+            parse(r#"{"command":"start_mission","args":{"mission":"fire01"}}"#).unwrap(),
+            HeadsetCommand::StartMission {
+                mission: "fire01".into()
+            }
+        );
+        assert_eq!(
+            parse(r#"{"command":"set_stream_quality","args":{"quality":"half"}}"#).unwrap(),
+            HeadsetCommand::SetStreamQuality {
+                quality: StreamQuality::Half
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_commands_and_fields() {
+        assert!(parse(r#"{"command":"reboot"}"#).is_err());
+        assert!(parse(r#"{"command":"stop","force":true}"#).is_err());
+        assert!(parse(r#"{"command":"start_mission","args":{"mission":"a","speed":2}}"#).is_err());
+        assert!(parse(r#"{"command":"set_stream_quality","args":{"quality":"ultra"}}"#).is_err());
+    }
+
+    #[test]
+    fn wire_text_matches_the_client() {
+        let quality = |quality| HeadsetCommand::SetStreamQuality { quality };
+        let cases = [
+            (
+                HeadsetCommand::StartMission {
+                    mission: "fire01".into(),
+                },
+                "start fire01",
+            ),
+            (HeadsetCommand::Stop, "stop"),
+            (HeadsetCommand::Pause, "pause"),
+            (
+                HeadsetCommand::ChangeLanguage {
+                    language: "zh".into(),
+                },
+                "language zh",
+            ),
+            (HeadsetCommand::Recenter, "recenter"),
+            (HeadsetCommand::RequestHistory, "export"),
+            (quality(StreamQuality::Full), "screen1"),
+            (quality(StreamQuality::Half), "screen2"),
+            (quality(StreamQuality::Thumbnail), "screen5"),
+        ];
+
+        for (command, wire) in cases {
+            assert_eq!(command.to_wire(), wire);
+        }
+    }
+
+    #[test]
+    fn arguments_must_be_one_word() {
+        let mission = |mission: &str| HeadsetCommand::StartMission {
+            mission: mission.into(),
+        };
+        assert!(mission("fire01").validate().is_ok());
+        assert!(mission("").validate().is_err());
+        assert!(mission("fire 01").validate().is_err());
+        assert!(HeadsetCommand::ChangeLanguage {
+            language: " ".into()
+        }
+        .validate()
+        .is_err());
+    }
+}
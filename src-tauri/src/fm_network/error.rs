@@ -0,0 +1,41 @@
+use std::{fmt::Display, net::SocketAddr};
+
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", content = "detail")]
+pub enum NetworkError {
+    AlreadyRunning,
+    NotRunning,
+    Bind {
+        addr: SocketAddr,
+        reason: String,
+    },
+    Socket(String),
+    InvalidAddress(String),
+    InvalidCommand(String),
+    /// A broadcast is one datagram nobody acknowledges, it can't be sent reliably.
+    ReliableBroadcast,
+}
+
+impl Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AlreadyRunning => write!(f, "UDP server is already running"),
+            Self::NotRunning => write!(f, "UDP server is not running"),
+            Self::Bind { addr, reason } => write!(f, "failed to bind {}: {}", addr, reason),
+            Self::Socket(reason) => write!(f, "socket error: {}", reason),
+            Self::InvalidAddress(addr) => write!(f, "invalid address {}", addr),
+            Self::InvalidCommand(reason) => write!(f, "invalid command, {}", reason),
+            Self::ReliableBroadcast => write!(f, "broadcasts can't be sent reliably"),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+impl From<std::io::Error> for NetworkError {
+    fn from(e: std::io::Error) -> Self {
+        Self::Socket(e.to_string())
+    }
+}
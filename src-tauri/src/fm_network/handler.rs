@@ -1,22 +1,94 @@
-use serde_json::Value;
-use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::ops::Deref;
 use std::sync::Arc;
-use tokio::{net::UdpSocket, task::JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::fm_network::action::{ClientChangedDetail, FMAction, HistoryDetail, JpegDecodedDetail};
+use serde::Serialize;
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle};
+
+use crate::config::{self, AppConfig, RetryPolicy};
+use crate::fm_network::action::{
+    ClientChangedDetail, DeviceIdentifiedDetail, FMAction, HistoryDetail,
+    HistoryTransferFailedDetail, JpegDecodedDetail, MalformedPacketDetail,
+};
 use crate::fm_network::client::ClientStatus;
-use crate::fm_network::jpeg_decoder::{JPEGDecoder, JPEGHeader};
+use crate::fm_network::command::HeadsetCommand;
+use crate::fm_network::error::NetworkError;
+use crate::fm_network::history_decoder::{HistoryChunkHeader, HistoryDecoder, TransferError};
+use crate::fm_network::jpeg_decoder::{DecoderStats, JPEGDecoder, JPEGHeader};
 use crate::fm_network::packet::FMPacket;
-use crate::fm_network::{
-    emit_action, send, CLIENTS, FM_CLIENT_PORT, FM_SERVER_PORT, JPEG_DECODERS,
-};
+use crate::fm_network::stream_stats::StreamStatsTracker;
+use crate::fm_network::Shared;
+use crate::play_history::PlayHistory;
 
 pub(crate) struct SocketHandler {
     socket: Option<Arc<UdpSocket>>,
     task: Option<JoinHandle<()>>,
     client_live_checker: Option<JoinHandle<()>>,
+    stats_reporter: Option<JoinHandle<()>>,
+    client_port: u16,
+    broadcast_addr: SocketAddr,
+    local_addr: Option<SocketAddr>,
+    started_at: Option<Instant>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NetworkStatus {
+    running: bool,
+    bound_address: Option<SocketAddr>,
+    uptime_secs: Option<u64>,
+    receiver_alive: bool,
+    live_checker_alive: bool,
+}
+
+/// How long to keep collecting histories after the last one arrived.
+const HISTORY_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum HistoryRequestOutcome {
+    /// At least one history arrived and was stored, `failed` lists the ones that weren't.
+    Received {
+        attempts: u32,
+        sessions: Vec<StoredHistory>,
+        failed: Vec<String>,
+    },
+    TimedOut {
+        attempts: u32,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+/// A history stored in reply to a history request.
+#[derive(Serialize, Clone, Debug)]
+pub struct StoredHistory {
+    pub user_id: String,
+    pub session_id: String,
+    /// The same session was stored before.
+    pub duplicate: bool,
+}
+
+/// What a pending history request is told about each history `addr` sent.
+pub(crate) type HistoryReply = Result<StoredHistory, String>;
+
+/// A play history received from a headset, queued for [`crate::fm_network::FmNetwork::take_histories`].
+#[derive(Debug)]
+pub struct ReceivedHistory {
+    pub addr: SocketAddr,
+    pub history: PlayHistory,
+    /// The history request waiting on `addr`, if any.
+    request: Option<mpsc::UnboundedSender<HistoryReply>>,
+}
+
+impl ReceivedHistory {
+    /// Tells a pending history request whether the history was stored.
+    pub fn confirm(&self, stored: Result<StoredHistory, String>) {
+        if let Some(request) = &self.request {
+            let _ = request.send(stored);
+        }
+    }
 }
 
 impl SocketHandler {
@@ -25,37 +97,67 @@ impl SocketHandler {
             socket: None,
             task: None,
             client_live_checker: None,
+            stats_reporter: None,
+            client_port: AppConfig::default().client_port,
+            broadcast_addr: AppConfig::default().broadcast_addr(),
+            local_addr: None,
+            started_at: None,
         }
     }
 
-    pub(crate) async fn run(&mut self) -> bool {
-        if let Some(_) = self.socket {
-            return false;
+    pub(crate) async fn run(&mut self, shared: Arc<Shared>) -> Result<SocketAddr, NetworkError> {
+        if self.socket.is_some() || self.task.is_some() {
+            return Err(NetworkError::AlreadyRunning);
         }
 
-        if let Some(_) = self.task {
-            return false;
-        }
+        let config = config::current().await;
+        let bind_addr = config.bind_addr();
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .map_err(|e| NetworkError::Bind {
+                addr: bind_addr,
+                reason: e.to_string(),
+            })?;
+        socket.set_broadcast(true)?;
+        let local_addr = socket.local_addr()?;
 
-        let socket_result = UdpSocket::bind(format!("0.0.0.0:{}", FM_SERVER_PORT)).await;
-        if let Ok(socket) = socket_result {
-            self.init(socket);
-            return true;
-        }
+        self.init(socket, &config, shared);
+        self.local_addr = Some(local_addr);
+        self.started_at = Some(Instant::now());
+        Ok(local_addr)
+    }
+
+    pub(crate) fn is_running(&self) -> bool {
+        self.socket.is_some()
+    }
+
+    pub(crate) fn status(&self) -> NetworkStatus {
+        let is_alive =
+            |task: &Option<JoinHandle<()>>| task.as_ref().is_some_and(|task| !task.is_finished());
 
-        return false;
+        NetworkStatus {
+            running: self.is_running(),
+            bound_address: self.local_addr,
+            uptime_secs: self.started_at.map(|at| at.elapsed().as_secs()),
+            receiver_alive: is_alive(&self.task),
+            live_checker_alive: is_alive(&self.client_live_checker),
+        }
     }
 
-    fn init(&mut self, socket: UdpSocket) {
+    fn init(&mut self, socket: UdpSocket, config: &AppConfig, shared: Arc<Shared>) {
         let arc_socket = Arc::new(socket);
         let socket = arc_socket.clone();
+        let buffer_size = config.recv_buffer_size;
+        let check_interval = config.live_check_interval();
+        let client_timeout = config.client_timeout();
 
+        let receiver_shared = shared.clone();
         let task = tokio::task::spawn(async move {
-            let mut buf = [0; 8192];
+            let mut buf = vec![0; buffer_size];
             loop {
                 match arc_socket.recv_from(&mut buf).await {
                     Ok((len, addr)) => {
-                        Self::on_receive_raw(&buf, len, addr).await;
+                        Self::on_receive_raw(&receiver_shared, &buf, len, addr).await;
                     }
                     Err(e) => {
                         eprintln!("Error receiving data: {}", e);
@@ -64,14 +166,16 @@ impl SocketHandler {
             }
         });
 
+        let checker_shared = shared.clone();
         let live_checker = tokio::task::spawn(async move {
+            let shared = checker_shared;
             loop {
-                tokio::time::sleep(tokio::time::Duration::from_secs(3)).await;
-                let mut clients = CLIENTS.write().await;
+                tokio::time::sleep(check_interval).await;
+                let mut clients = shared.clients.write().await;
                 let mut pending_remove = Vec::<SocketAddr>::new();
 
                 for (addr, status) in clients.iter() {
-                    if !status.is_active() {
+                    if !status.is_active(client_timeout) {
                         pending_remove.push(*addr);
                     }
                 }
@@ -82,7 +186,41 @@ impl SocketHandler {
 
                 for addr in pending_remove.iter() {
                     clients.remove(addr);
-                    emit_action(FMAction::ClientChanged(ClientChangedDetail::removed(*addr))).await;
+                }
+                drop(clients);
+
+                for addr in pending_remove.iter() {
+                    shared.jpeg_decoders.write().await.remove(addr);
+                    shared.stream_stats.write().await.remove(addr);
+                    let aborted = shared.history_decoders.write().await.remove(addr);
+                    if let Some(decoder) = aborted {
+                        report_transfer_failures(&shared, *addr, decoder.abort("client timed out"));
+                    }
+                    shared
+                        .emit_action(FMAction::ClientChanged(ClientChangedDetail::removed(*addr)));
+                }
+
+                let now = Instant::now();
+                let expired: Vec<(SocketAddr, Vec<TransferError>)> = shared
+                    .history_decoders
+                    .write()
+                    .await
+                    .iter_mut()
+                    .map(|(addr, decoder)| (*addr, decoder.expire_transfers(now)))
+                    .collect();
+                for (addr, failures) in expired {
+                    report_transfer_failures(&shared, addr, failures);
+                }
+            }
+        });
+
+        let stats_reporter = tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+                expire_jpeg_frames(&shared).await;
+                let stats = shared.stream_stats().await;
+                if !stats.is_empty() {
+                    shared.emit_action(FMAction::StreamStats(stats));
                 }
             }
         });
@@ -90,6 +228,9 @@ impl SocketHandler {
         self.task = Some(task);
         self.socket = Some(socket);
         self.client_live_checker = Some(live_checker);
+        self.stats_reporter = Some(stats_reporter);
+        self.client_port = config.client_port;
+        self.broadcast_addr = config.broadcast_addr();
 
         println!("SocketHandler initialized at {:?}", self.socket);
     }
@@ -105,95 +246,354 @@ impl SocketHandler {
         }
         self.client_live_checker = None;
 
+        if let Some(stats_reporter) = self.stats_reporter.take() {
+            stats_reporter.abort();
+        }
+
+        self.local_addr = None;
+        self.started_at = None;
+
         println!("SocketHandler stopped");
     }
 
-    async fn on_receive_raw(buf: &[u8], len: usize, addr: SocketAddr) {
-        let mut clients = CLIENTS.write().await;
-        let origin_len = clients.len();
-        let client = clients
-            .entry(addr)
-            .or_insert_with(|| ClientStatus::new(addr));
+    async fn on_receive_raw(shared: &Shared, buf: &[u8], len: usize, addr: SocketAddr) {
+        let is_new_client = {
+            let mut clients = shared.clients.write().await;
+            let origin_len = clients.len();
+            let client = clients
+                .entry(addr)
+                .or_insert_with(|| ClientStatus::new(addr));
 
-        client.update_heartbeat();
-        let new_len = clients.len();
+            client.update_heartbeat();
+            clients.len() > origin_len
+        };
 
-        if new_len > origin_len {
-            emit_action(FMAction::ClientChanged(ClientChangedDetail::added(addr))).await;
+        if is_new_client {
+            shared.emit_action(FMAction::ClientChanged(ClientChangedDetail::added(addr)));
         }
 
-        send(addr.into(), FMPacket::Heartbeat).await;
+        if let Err(e) = shared.send(addr.into(), FMPacket::Heartbeat).await {
+            eprintln!("Error sending heartbeat to {}: {}", addr, e);
+        }
 
         let data = &buf[..len];
 
-        let packet = Arc::new(FMPacket::new(data));
+        let packet = match FMPacket::decode(data) {
+            Ok(packet) => Arc::new(packet),
+            Err(e) => {
+                report_malformed(shared, addr, e.to_string()).await;
+                return;
+            }
+        };
         let action = FMAction::PacketReceived {
             addr,
             packet: packet.clone(),
         };
-        emit_action(action).await;
+        shared.emit_action(action);
 
         match packet.deref() {
             FMPacket::JPEGPacket { header, data } => {
-                decode_jpeg_packet(addr, header.to_owned(), data).await
+                decode_jpeg_packet(shared, addr, header.to_owned(), data).await
             }
             FMPacket::PlayHistoryPacket { json } => {
-                decode_play_history(&json).await;
+                decode_play_history(shared, addr, json).await;
+            }
+            FMPacket::HistoryChunk { header, data } => {
+                decode_history_chunk(shared, addr, *header, data).await;
+            }
+            FMPacket::Hello { device_id } => {
+                identify_device(shared, addr, device_id).await;
+            }
+            FMPacket::Ack { seq } => {
+                shared.commands.lock().await.acknowledge(*seq, addr.ip());
             }
             _ => {}
         };
     }
 
-    pub(crate) async fn send(&self, mut addr: SocketAddr, packet: FMPacket) {
-        if let Some(socket) = &self.socket {
-            if let Some(send_bytes) = packet.to_bytes() {
-                addr.set_port(FM_CLIENT_PORT);
-                match socket.send_to(send_bytes.as_slice(), addr).await {
-                    Ok(_) => {
-                        if let FMPacket::StringPacket { data } = packet {
-                            dbg!(&data, addr);
-                        };
-                    }
-                    Err(e) => {
-                        eprintln!("Error sending data: {}", e);
-                    }
-                }
+    pub(crate) async fn send(
+        &self,
+        mut addr: SocketAddr,
+        packet: FMPacket,
+    ) -> Result<(), NetworkError> {
+        let socket = self.socket.as_ref().ok_or(NetworkError::NotRunning)?;
+
+        let send_bytes = packet.encode();
+        addr.set_port(self.client_port);
+        socket.send_to(send_bytes.as_slice(), addr).await?;
+
+        if let FMPacket::StringPacket { data } = packet {
+            dbg!(&data, addr);
+        };
+        Ok(())
+    }
+
+    /// Sends one datagram to the configured broadcast or multicast address.
+    pub(crate) async fn broadcast(&self, packet: FMPacket) -> Result<SocketAddr, NetworkError> {
+        let socket = self.socket.as_ref().ok_or(NetworkError::NotRunning)?;
+        socket
+            .send_to(packet.encode().as_slice(), self.broadcast_addr)
+            .await?;
+        Ok(self.broadcast_addr)
+    }
+}
+
+/// Asks the headset at `addr` for its stored histories with [`HeadsetCommand::RequestHistory`],
+/// resending with backoff until one arrives.
+///
+/// The replies go through [`decode_play_history`] like pushed histories and count once stored;
+/// the request completes once no further history arrived for [`HISTORY_IDLE_TIMEOUT`].
+pub(crate) async fn request_history(
+    shared: &Shared,
+    addr: SocketAddr,
+    policy: &RetryPolicy,
+) -> HistoryRequestOutcome {
+    let (sender, mut replies) = mpsc::unbounded_channel();
+    {
+        let mut requests = shared.history_requests.lock().await;
+        if requests.get(&addr.ip()).is_some_and(|s| !s.is_closed()) {
+            return HistoryRequestOutcome::Failed {
+                reason: format!("a history request to {} is already pending", addr.ip()),
+            };
+        }
+        requests.insert(addr.ip(), sender);
+    }
+
+    let outcome = collect_histories(shared, addr, policy, &mut replies).await;
+    shared.history_requests.lock().await.remove(&addr.ip());
+    outcome
+}
+
+async fn collect_histories(
+    shared: &Shared,
+    addr: SocketAddr,
+    policy: &RetryPolicy,
+    replies: &mut mpsc::UnboundedReceiver<HistoryReply>,
+) -> HistoryRequestOutcome {
+    let stopped = || HistoryRequestOutcome::Failed {
+        reason: "network stopped before the history arrived".into(),
+    };
+    let mut timeout = policy.initial_timeout();
+    let request = FMPacket::StringPacket {
+        data: HeadsetCommand::RequestHistory.to_wire(),
+    };
+
+    for attempt in 1..=policy.max_attempts {
+        if let Err(e) = shared.send(addr.into(), request.clone()).await {
+            return HistoryRequestOutcome::Failed {
+                reason: e.to_string(),
+            };
+        }
+
+        let first = match tokio::time::timeout(timeout, replies.recv()).await {
+            Ok(Some(reply)) => reply,
+            Ok(None) => return stopped(),
+            Err(_) => {
+                timeout = policy.next_timeout(timeout);
+                continue;
             }
+        };
+
+        let mut sessions = Vec::new();
+        let mut failed = Vec::new();
+        let mut reply = Some(first);
+        while let Some(stored) = reply {
+            match stored {
+                Ok(session) => sessions.push(session),
+                Err(reason) => failed.push(reason),
+            }
+            reply = tokio::time::timeout(HISTORY_IDLE_TIMEOUT, replies.recv())
+                .await
+                .ok()
+                .flatten();
+        }
+
+        if sessions.is_empty() {
+            return HistoryRequestOutcome::Failed {
+                reason: failed.join("; "),
+            };
         }
+        return HistoryRequestOutcome::Received {
+            attempts: attempt,
+            sessions,
+            failed,
+        };
+    }
+
+    HistoryRequestOutcome::TimedOut {
+        attempts: policy.max_attempts,
     }
 }
 
-async fn decode_jpeg_packet(addr: SocketAddr, header: JPEGHeader, data: &Vec<u8>) {
-    let mut decoders = JPEG_DECODERS.write().await;
+/// Counts a rejected datagram or frame against its client and notifies listeners.
+async fn report_malformed(shared: &Shared, addr: SocketAddr, reason: String) {
+    eprintln!("Malformed packet from {}: {}", addr, reason);
 
-    let decoder = decoders
-        .entry(addr)
-        .or_insert_with(|| JPEGDecoder::new(header));
+    let total = {
+        let mut clients = shared.clients.write().await;
+        match clients.get_mut(&addr) {
+            Some(client) => {
+                client.malformed_packets += 1;
+                client.malformed_packets
+            }
+            None => 1,
+        }
+    };
 
-    match decoder.append_data(header, data) {
-        Ok(Some(decoded_data)) => {
-            emit_action(FMAction::JpegDecoded(JpegDecodedDetail::new(
-                addr,
-                decoded_data,
-            )))
-            .await
+    shared.emit_action(FMAction::MalformedPacket(MalformedPacketDetail::new(
+        addr, reason, total,
+    )));
+}
+
+async fn identify_device(shared: &Shared, addr: SocketAddr, device_id: &str) {
+    if device_id.is_empty() {
+        report_malformed(shared, addr, "hello packet without device id".into()).await;
+        return;
+    }
+
+    let device = {
+        let mut registry = shared.registry.write().await;
+        let (device, changed) = registry.bind(device_id, addr);
+        if changed {
+            registry.save().await;
         }
-        Err(e) => {
-            eprintln!("Error appending JPEG data: {}", e);
+        device
+    };
+
+    if let Some(client) = shared.clients.write().await.get_mut(&addr) {
+        client.device_id = Some(device.device_id.clone());
+    }
+
+    shared.emit_action(FMAction::DeviceIdentified(DeviceIdentifiedDetail::new(
+        addr, device,
+    )));
+}
+
+/// Drops timed out frames so a stream that stalled still reports them as abandoned.
+async fn expire_jpeg_frames(shared: &Shared) {
+    let now = Instant::now();
+    let decoder_stats: Vec<(SocketAddr, DecoderStats)> = shared
+        .jpeg_decoders
+        .write()
+        .await
+        .iter_mut()
+        .map(|(addr, decoder)| {
+            decoder.expire_frames(now);
+            (*addr, decoder.stats())
+        })
+        .collect();
+
+    let mut stats = shared.stream_stats.write().await;
+    for (addr, decoder_stats) in decoder_stats {
+        if let Some(tracker) = stats.get_mut(&addr) {
+            tracker.update_decoder(decoder_stats);
+        }
+    }
+}
+
+async fn decode_jpeg_packet(shared: &Shared, addr: SocketAddr, header: JPEGHeader, data: &[u8]) {
+    let mut decoders = shared.jpeg_decoders.write().await;
+    let decoder = decoders.entry(addr).or_insert_with(JPEGDecoder::new);
+    let result = decoder.append_data(header, data);
+    let decoder_stats = decoder.stats();
+    drop(decoders);
+
+    {
+        let mut stats = shared.stream_stats.write().await;
+        let tracker = stats
+            .entry(addr)
+            .or_insert_with(|| StreamStatsTracker::new(addr));
+        tracker.record_chunk(data.len());
+        tracker.update_decoder(decoder_stats);
+        if let Ok(Some(frame)) = &result {
+            tracker.record_frame(frame);
+        }
+    }
+
+    match result {
+        Ok(Some(frame)) => {
+            shared.emit_action(FMAction::JpegDecoded(JpegDecodedDetail::new(
+                addr,
+                Arc::new(frame.data),
+            )));
         }
-        _ => {}
+        Ok(None) => {}
+        Err(e) => report_malformed(shared, addr, e).await,
+    }
+}
+
+async fn decode_history_chunk(
+    shared: &Shared,
+    addr: SocketAddr,
+    header: HistoryChunkHeader,
+    data: &[u8],
+) {
+    let result = shared
+        .history_decoders
+        .write()
+        .await
+        .entry(addr)
+        .or_insert_with(HistoryDecoder::new)
+        .append_data(header, data);
+
+    match result {
+        Ok(Some(json)) => decode_play_history(shared, addr, &json).await,
+        Ok(None) => {}
+        Err(e) => report_transfer_failures(shared, addr, vec![e]),
+    }
+}
+
+fn report_transfer_failures(shared: &Shared, addr: SocketAddr, failures: Vec<TransferError>) {
+    for failure in failures {
+        eprintln!(
+            "History transfer {} from {} failed: {}",
+            failure.id, addr, failure.reason
+        );
+        shared.emit_action(FMAction::HistoryTransferFailed(
+            HistoryTransferFailedDetail::new(addr, failure.id, failure.reason),
+        ));
     }
 }
 
-async fn decode_play_history(json: &str) {
-    if let Ok(play_history_map) = serde_json::from_str::<HashMap<String, Value>>(json) {
-        if let Some(user_id) = play_history_map.get("userId") {
-            let user_id = user_id.as_str().unwrap_or_else(|| "unknown");
-            emit_action(FMAction::HistoryReceived(HistoryDetail::new(
-                user_id,
-                play_history_map.to_owned(),
-            )))
-            .await;
+async fn decode_play_history(shared: &Shared, addr: SocketAddr, json: &str) {
+    let request = shared
+        .history_requests
+        .lock()
+        .await
+        .get(&addr.ip())
+        .cloned();
+    let history = match PlayHistory::from_json(json) {
+        Ok(history) => history,
+        Err(e) => {
+            let reason = format!("invalid play history, {}", e);
+            if let Some(request) = request {
+                let _ = request.send(Err(reason.clone()));
+            }
+            report_malformed(shared, addr, reason).await;
+            return;
         }
+    };
+    // only reported, dropping a trainee's session over a bad value would lose it for good;
+    // histories that don't parse at all were rejected above
+    if let Err(e) = history.validate() {
+        report_malformed(
+            shared,
+            addr,
+            format!("play history {}: {}", history.user_id, e),
+        )
+        .await;
+    }
+
+    shared.emit_action(FMAction::HistoryReceived(HistoryDetail::new(
+        addr,
+        history.clone(),
+    )));
+    let received = ReceivedHistory {
+        addr,
+        history,
+        request,
+    };
+    if shared.histories.send(received).is_err() {
+        eprintln!("History queue closed, history from {} not stored", addr);
     }
 }
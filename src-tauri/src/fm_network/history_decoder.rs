@@ -0,0 +1,429 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::Read,
+    time::{Duration, Instant},
+};
+
+use flate2::{read::GzDecoder, Crc};
+use serde::Serialize;
+
+use crate::fm_network::chunks::ChunkAssembler;
+
+/// How many history transfers may be reassembled at the same time per client.
+const MAX_PENDING_TRANSFERS: usize = 4;
+/// Incomplete transfers older than this are dropped and reported; finished transfer ids are
+/// remembered this long so their late chunks don't start a new transfer.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Size of the serialized [`HistoryChunkHeader`] that follows the packet meta bytes.
+pub const HISTORY_HEADER_LEN: usize = 17;
+
+/// Upper bound for a whole history payload as sent, anything larger is treated as garbage.
+pub const MAX_HISTORY_LEN: i32 = 4 * 1024 * 1024;
+
+/// Framing of one piece of a play history too large for a single datagram.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HistoryChunkHeader {
+    pub id: i32,     // 0 - 3
+    pub length: i32, // 4 - 7
+    pub offset: i32, // 8 - 11
+    /// CRC32 of the whole payload as sent, i.e. before gzip decompression.
+    pub crc32: u32, // 12 - 15
+    pub gzip: bool,  // 16
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum HistoryHeaderError {
+    Truncated {
+        actual: usize,
+    },
+    InvalidLength(i32),
+    NegativeOffset(i32),
+    ChunkOutOfBounds {
+        offset: i32,
+        len: usize,
+        length: i32,
+    },
+}
+
+impl Display for HistoryHeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated { actual } => {
+                write!(
+                    f,
+                    "header needs {} bytes, got {}",
+                    HISTORY_HEADER_LEN, actual
+                )
+            }
+            Self::InvalidLength(length) => write!(
+                f,
+                "history length {} must be between 1 and {}",
+                length, MAX_HISTORY_LEN
+            ),
+            Self::NegativeOffset(offset) => write!(f, "negative chunk offset {}", offset),
+            Self::ChunkOutOfBounds {
+                offset,
+                len,
+                length,
+            } => write!(
+                f,
+                "chunk of {} bytes at offset {} exceeds history length {}",
+                len, offset, length
+            ),
+        }
+    }
+}
+
+impl std::error::Error for HistoryHeaderError {}
+
+impl HistoryChunkHeader {
+    /// Reads a header from the first [`HISTORY_HEADER_LEN`] bytes of `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, HistoryHeaderError> {
+        let data: &[u8; HISTORY_HEADER_LEN] = data
+            .get(..HISTORY_HEADER_LEN)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(HistoryHeaderError::Truncated { actual: data.len() })?;
+
+        let read_bytes = |at: usize| [data[at], data[at + 1], data[at + 2], data[at + 3]];
+
+        let header = Self {
+            id: i32::from_le_bytes(read_bytes(0)),
+            length: i32::from_le_bytes(read_bytes(4)),
+            offset: i32::from_le_bytes(read_bytes(8)),
+            crc32: u32::from_le_bytes(read_bytes(12)),
+            gzip: data[16] != 0,
+        };
+
+        if !(1..=MAX_HISTORY_LEN).contains(&header.length) {
+            return Err(HistoryHeaderError::InvalidLength(header.length));
+        }
+        if header.offset < 0 {
+            return Err(HistoryHeaderError::NegativeOffset(header.offset));
+        }
+
+        Ok(header)
+    }
+
+    /// Checks that a chunk of `len` bytes at this header's offset stays inside the payload.
+    pub fn check_chunk(&self, len: usize) -> Result<(), HistoryHeaderError> {
+        let end_at = (self.offset as usize).checked_add(len);
+        match end_at {
+            Some(end_at) if self.offset >= 0 && end_at <= self.length.max(0) as usize => Ok(()),
+            _ => Err(HistoryHeaderError::ChunkOutOfBounds {
+                offset: self.offset,
+                len,
+                length: self.length,
+            }),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; HISTORY_HEADER_LEN] {
+        let mut bytes = [0; HISTORY_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&self.id.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.length.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.crc32.to_le_bytes());
+        bytes[16] = self.gzip as u8;
+        bytes
+    }
+}
+
+/// A history transfer that could not be completed.
+#[derive(Clone, Debug)]
+pub struct TransferError {
+    pub id: i32,
+    pub reason: String,
+}
+
+impl TransferError {
+    fn new(id: i32, reason: impl Into<String>) -> Self {
+        Self {
+            id,
+            reason: reason.into(),
+        }
+    }
+}
+
+/// Reassembles chunked play histories sent by one client.
+pub struct HistoryDecoder {
+    transfers: HashMap<i32, PendingTransfer>,
+    /// Completed, failed or rejected transfers by when they finished, their chunks are ignored.
+    finished: HashMap<i32, Instant>,
+}
+
+struct PendingTransfer {
+    header: HistoryChunkHeader,
+    chunks: ChunkAssembler,
+}
+
+impl HistoryDecoder {
+    pub fn new() -> Self {
+        Self {
+            transfers: HashMap::new(),
+            finished: HashMap::new(),
+        }
+    }
+
+    /// Adds a chunk, returns the history JSON once every byte arrived and passed the checks.
+    ///
+    /// Each transfer fails at most once, later chunks of a finished transfer are ignored.
+    pub fn append_data(
+        &mut self,
+        header: HistoryChunkHeader,
+        data: &[u8],
+    ) -> Result<Option<String>, TransferError> {
+        if self.is_late(header.id, Instant::now()) {
+            return Ok(None);
+        }
+        header
+            .check_chunk(data.len())
+            .map_err(|e| TransferError::new(header.id, e.to_string()))?;
+
+        if !self.transfers.contains_key(&header.id) && self.transfers.len() >= MAX_PENDING_TRANSFERS
+        {
+            self.finish(header.id);
+            return Err(TransferError::new(
+                header.id,
+                format!("more than {} transfers in flight", MAX_PENDING_TRANSFERS),
+            ));
+        }
+
+        let transfer = self
+            .transfers
+            .entry(header.id)
+            .or_insert_with(|| PendingTransfer {
+                header,
+                chunks: ChunkAssembler::new(header.length as usize),
+            });
+
+        if (header.length, header.crc32, header.gzip)
+            != (
+                transfer.header.length,
+                transfer.header.crc32,
+                transfer.header.gzip,
+            )
+        {
+            self.transfers.remove(&header.id);
+            self.finish(header.id);
+            return Err(TransferError::new(
+                header.id,
+                "chunk header disagrees with the first chunk of the transfer",
+            ));
+        }
+
+        transfer.chunks.insert(header.offset as usize, data);
+        if !transfer.chunks.is_complete() {
+            return Ok(None);
+        }
+
+        let transfer = self
+            .transfers
+            .remove(&header.id)
+            .expect("completed transfer is pending");
+        self.finish(header.id);
+        transfer.into_json().map(Some)
+    }
+
+    /// Drops every transfer that waited longer than [`TRANSFER_TIMEOUT`] and returns why.
+    pub fn expire_transfers(&mut self, now: Instant) -> Vec<TransferError> {
+        self.finished
+            .retain(|_, finished_at| now.duration_since(*finished_at) < TRANSFER_TIMEOUT);
+
+        let expired: Vec<i32> = self
+            .transfers
+            .iter()
+            .filter(|(_, transfer)| {
+                now.duration_since(transfer.chunks.started_at()) >= TRANSFER_TIMEOUT
+            })
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in &expired {
+            self.finished.insert(*id, now);
+        }
+        expired
+            .into_iter()
+            .filter_map(|id| self.transfers.remove(&id))
+            .map(|transfer| transfer.incomplete("timed out"))
+            .collect()
+    }
+
+    fn is_late(&self, id: i32, now: Instant) -> bool {
+        self.finished
+            .get(&id)
+            .is_some_and(|finished_at| now.duration_since(*finished_at) < TRANSFER_TIMEOUT)
+    }
+
+    fn finish(&mut self, id: i32) {
+        self.finished.insert(id, Instant::now());
+    }
+
+    /// Drops every pending transfer, e.g. because the client went away.
+    pub fn abort(self, reason: &str) -> Vec<TransferError> {
+        self.transfers
+            .into_values()
+            .map(|transfer| transfer.incomplete(reason))
+            .collect()
+    }
+}
+
+impl PendingTransfer {
+    fn incomplete(&self, reason: &str) -> TransferError {
+        TransferError::new(
+            self.header.id,
+            format!(
+                "{} with {} of {} bytes received",
+                reason,
+                self.chunks.received(),
+                self.chunks.len()
+            ),
+        )
+    }
+
+    fn into_json(self) -> Result<String, TransferError> {
+        let id = self.header.id;
+        let data = self.chunks.into_data();
+
+        let mut crc = Crc::new();
+        crc.update(&data);
+        if crc.sum() != self.header.crc32 {
+            return Err(TransferError::new(
+                id,
+                format!(
+                    "checksum mismatch, expected {:08x}, got {:08x}",
+                    self.header.crc32,
+                    crc.sum()
+                ),
+            ));
+        }
+
+        let data = if self.header.gzip {
+            let mut buf = Vec::new();
+            let mut decoder = GzDecoder::new(&data[..]);
+            if let Err(e) = decoder.read_to_end(&mut buf) {
+                return Err(TransferError::new(
+                    id,
+                    format!("Error decoding history data: {}", e),
+                ));
+            }
+            buf
+        } else {
+            data
+        };
+
+        String::from_utf8(data).map_err(|e| TransferError::new(id, e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    const JSON: &str = r#"{"userId":"u1","missionDatas":[]}"#;
+
+    /// Splits `payload` into `chunk_len` pieces framed like a headset would.
+    fn chunks(
+        id: i32,
+        payload: &[u8],
+        gzip: bool,
+        chunk_len: usize,
+    ) -> Vec<(HistoryChunkHeader, Vec<u8>)> {
+        let mut crc = Crc::new();
+        crc.update(payload);
+        payload
+            .chunks(chunk_len)
+            .enumerate()
+            .map(|(i, data)| {
+                let header = HistoryChunkHeader {
+                    id,
+                    length: payload.len() as i32,
+                    offset: (i * chunk_len) as i32,
+                    crc32: crc.sum(),
+                    gzip,
+                };
+                (header, data.to_vec())
+            })
+            .collect()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn header_round_trips() {
+        let (header, _) = chunks(9, JSON.as_bytes(), true, 8).remove(1);
+        assert_eq!(
+            HistoryChunkHeader::from_bytes(&header.to_bytes()),
+            Ok(header)
+        );
+    }
+
+    #[test]
+    fn reassembles_plain_and_gzipped_histories() {
+        for (id, payload, gzipped) in [
+            (1, JSON.as_bytes().to_vec(), false),
+            (2, gzip(JSON.as_bytes()), true),
+        ] {
+            let mut decoder = HistoryDecoder::new();
+            let mut pieces = chunks(id, &payload, gzipped, 7);
+            pieces.reverse();
+            let last = pieces.pop().unwrap();
+            for (header, data) in pieces {
+                assert_eq!(decoder.append_data(header, &data).unwrap(), None);
+            }
+            let json = decoder.append_data(last.0, &last.1).unwrap();
+            assert_eq!(json.as_deref(), Some(JSON));
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch_fails_the_transfer() {
+        let mut decoder = HistoryDecoder::new();
+        let (mut header, data) = chunks(3, JSON.as_bytes(), false, JSON.len()).remove(0);
+        header.crc32 ^= 1;
+
+        let error = decoder.append_data(header, &data).unwrap_err();
+        assert_eq!(error.id, 3);
+        assert!(error.reason.starts_with("checksum mismatch"));
+    }
+
+    #[test]
+    fn late_chunks_of_a_finished_transfer_are_ignored() {
+        let mut decoder = HistoryDecoder::new();
+        let pieces = chunks(4, JSON.as_bytes(), false, 10);
+        for (header, data) in &pieces {
+            decoder.append_data(*header, data).unwrap();
+        }
+
+        let (header, data) = &pieces[0];
+        assert_eq!(decoder.append_data(*header, data).unwrap(), None);
+        assert!(decoder
+            .expire_transfers(Instant::now() + TRANSFER_TIMEOUT)
+            .is_empty());
+    }
+
+    #[test]
+    fn rejects_a_transfer_over_the_limit_once() {
+        let mut decoder = HistoryDecoder::new();
+        for id in 0..MAX_PENDING_TRANSFERS as i32 {
+            let (header, data) = chunks(id, JSON.as_bytes(), false, 4).remove(0);
+            decoder.append_data(header, &data).unwrap();
+        }
+
+        let pieces = chunks(99, JSON.as_bytes(), false, 4);
+        assert!(decoder.append_data(pieces[0].0, &pieces[0].1).is_err());
+        assert_eq!(
+            decoder.append_data(pieces[1].0, &pieces[1].1).unwrap(),
+            None
+        );
+    }
+}
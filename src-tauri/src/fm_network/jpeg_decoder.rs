@@ -1,89 +1,419 @@
-use std::io::Read;
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::Read,
+    time::{Duration, Instant},
+};
 
 use flate2::read::GzDecoder;
+use serde::Serialize;
+
+use crate::fm_network::chunks::ChunkAssembler;
+
+/// How many frame ids may be reassembled at the same time per stream.
+const MAX_PENDING_FRAMES: usize = 4;
+/// Incomplete frames older than this are dropped.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(1);
+/// Ids this far behind the last completed frame are treated as late chunks,
+/// anything further back is assumed to be a restarted client. So is a stream with no
+/// frame completed for [`FRAME_TIMEOUT`], its ids may start over below the old ones.
+const STALE_ID_WINDOW: i32 = 64;
 
 pub struct JPEGDecoder {
+    frames: HashMap<i32, PendingFrame>,
+    /// Id of the last completed frame and when it completed.
+    last_completed: Option<(i32, Instant)>,
+    stats: DecoderStats,
+}
+
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+pub struct DecoderStats {
+    pub frames_completed: u64,
+    pub frames_dropped: u64,
+    pub duplicate_chunks: u64,
+    pub late_chunks: u64,
+}
+
+pub struct DecodedFrame {
+    pub data: Vec<u8>,
+    /// Size of the frame as it was sent, before gzip decompression.
+    pub compressed_len: usize,
+    /// Time between the first chunk arriving and the frame completing.
+    pub assembly_time: Duration,
+}
+
+struct PendingFrame {
     header: JPEGHeader,
-    data: Vec<u8>,
-    byte_received: i32,
+    chunks: ChunkAssembler,
 }
 
-#[derive(Clone, Copy, Debug)]
+/// Size of the serialized [`JPEGHeader`] that follows the packet meta bytes.
+pub const JPEG_HEADER_LEN: usize = 18;
+
+/// Upper bound for a single frame, anything larger is treated as garbage.
+pub const MAX_JPEG_FRAME_LEN: i32 = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct JPEGHeader {
-    // label: i32,          // 0 - 3
-    id: i32,     // 4 - 7
-    length: i32, // 8 - 11
-    offset: i32, // 12 - 15
-    gzip: bool,  // 16
-                 // color_reduction: u8, // 17
+    pub label: i32,          // 0 - 3
+    pub id: i32,             // 4 - 7
+    pub length: i32,         // 8 - 11
+    pub offset: i32,         // 12 - 15
+    pub gzip: bool,          // 16
+    pub color_reduction: u8, // 17
 }
 
-impl JPEGDecoder {
-    pub fn new(header: JPEGHeader) -> Self {
-        let data = vec![0; header.length as usize];
-        dbg!(header);
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum JPEGHeaderError {
+    Truncated {
+        actual: usize,
+    },
+    NegativeLength(i32),
+    NegativeOffset(i32),
+    LengthTooLarge(i32),
+    ChunkOutOfBounds {
+        offset: i32,
+        len: usize,
+        length: i32,
+    },
+}
 
+impl Display for JPEGHeaderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated { actual } => {
+                write!(f, "header needs {} bytes, got {}", JPEG_HEADER_LEN, actual)
+            }
+            Self::NegativeLength(length) => write!(f, "negative frame length {}", length),
+            Self::NegativeOffset(offset) => write!(f, "negative chunk offset {}", offset),
+            Self::LengthTooLarge(length) => write!(
+                f,
+                "frame length {} exceeds limit {}",
+                length, MAX_JPEG_FRAME_LEN
+            ),
+            Self::ChunkOutOfBounds {
+                offset,
+                len,
+                length,
+            } => write!(
+                f,
+                "chunk of {} bytes at offset {} exceeds frame length {}",
+                len, offset, length
+            ),
+        }
+    }
+}
+
+impl std::error::Error for JPEGHeaderError {}
+
+impl JPEGDecoder {
+    pub fn new() -> Self {
         Self {
-            data,
-            header,
-            byte_received: 0,
+            frames: HashMap::new(),
+            last_completed: None,
+            stats: DecoderStats::default(),
         }
     }
 
+    pub fn stats(&self) -> DecoderStats {
+        self.stats
+    }
+
     pub fn append_data(
         &mut self,
         header: JPEGHeader,
-        data: &Vec<u8>,
-    ) -> Result<Option<&Vec<u8>>, String> {
-        if header.id != self.header.id {
-            if self.byte_received != self.header.length {
-                println!(
-                    "Warning: JPEG ID mismatch. Expected {}, got {}. Resetting decoder.",
-                    self.header.id, header.id
-                );
-            }
-            self.header = header;
-            self.data = vec![0; header.length as usize];
-            self.byte_received = 0;
+        data: &[u8],
+    ) -> Result<Option<DecodedFrame>, String> {
+        self.append_data_at(header, data, Instant::now())
+    }
+
+    fn append_data_at(
+        &mut self,
+        header: JPEGHeader,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<Option<DecodedFrame>, String> {
+        self.expire_frames(now);
+
+        if self.is_late(header.id, now) {
+            self.stats.late_chunks += 1;
+            return Ok(None);
         }
 
-        let offset = header.offset as usize;
-        let data_len = data.len();
+        if header.length == 0 {
+            return Err(format!("Frame {} has no data", header.id));
+        }
+        header.check_chunk(data.len()).map_err(|e| e.to_string())?;
 
-        let end_at = offset + data_len;
-        if end_at > self.header.length as usize {
-            return Err("Data exceeds header length".into());
+        if !self.frames.contains_key(&header.id) && self.frames.len() >= MAX_PENDING_FRAMES {
+            self.drop_oldest_frame();
         }
 
-        self.data[offset..end_at].copy_from_slice(&data[..]);
-        self.byte_received += data_len as i32;
+        let frame = self
+            .frames
+            .entry(header.id)
+            .or_insert_with(|| PendingFrame::new(header));
 
-        if self.byte_received < self.header.length {
+        if header.length != frame.header.length {
+            return Err(format!(
+                "Frame {} length changed from {} to {}",
+                header.id, frame.header.length, header.length
+            ));
+        }
+
+        if !frame.chunks.insert(header.offset as usize, data) {
+            self.stats.duplicate_chunks += 1;
+            return Ok(None);
+        }
+
+        if !frame.chunks.is_complete() {
             return Ok(None);
         }
 
-        if self.header.gzip {
+        let frame = self
+            .frames
+            .remove(&header.id)
+            .expect("completed frame is pending");
+        self.complete(header.id, now);
+
+        frame.into_frame().map(Some)
+    }
+
+    /// Drops every incomplete frame that waited longer than [`FRAME_TIMEOUT`].
+    pub fn expire_frames(&mut self, now: Instant) {
+        let before = self.frames.len();
+        self.frames
+            .retain(|_, frame| now.duration_since(frame.chunks.started_at()) < FRAME_TIMEOUT);
+        self.stats.frames_dropped += (before - self.frames.len()) as u64;
+    }
+
+    fn is_late(&self, id: i32, now: Instant) -> bool {
+        match self.last_completed {
+            Some((last, at)) if now.duration_since(at) < FRAME_TIMEOUT => {
+                (0..STALE_ID_WINDOW).contains(&last.wrapping_sub(id))
+            }
+            _ => false,
+        }
+    }
+
+    fn drop_oldest_frame(&mut self) {
+        let oldest = self
+            .frames
+            .iter()
+            .min_by_key(|(_, frame)| frame.chunks.started_at())
+            .map(|(id, _)| *id);
+
+        if let Some(id) = oldest {
+            self.frames.remove(&id);
+            self.stats.frames_dropped += 1;
+        }
+    }
+
+    /// Marks `id` as shown; older frames still in flight can never be displayed.
+    fn complete(&mut self, id: i32, now: Instant) {
+        self.stats.frames_completed += 1;
+        self.last_completed = Some((id, now));
+
+        let before = self.frames.len();
+        self.frames
+            .retain(|pending, _| !(0..STALE_ID_WINDOW).contains(&id.wrapping_sub(*pending)));
+        self.stats.frames_dropped += (before - self.frames.len()) as u64;
+    }
+}
+
+impl PendingFrame {
+    fn new(header: JPEGHeader) -> Self {
+        Self {
+            header,
+            chunks: ChunkAssembler::new(header.length as usize),
+        }
+    }
+
+    fn into_frame(self) -> Result<DecodedFrame, String> {
+        let compressed_len = self.chunks.len();
+        let assembly_time = self.chunks.elapsed();
+        let data = self.chunks.into_data();
+
+        let data = if self.header.gzip {
             let mut buf = Vec::new();
-            let mut decoder = GzDecoder::new(&self.data[..]);
+            let mut decoder = GzDecoder::new(&data[..]);
             if let Err(e) = decoder.read_to_end(&mut buf) {
-                eprintln!("Error decoding JPEG data: {}", e);
+                return Err(format!("Error decoding JPEG data: {}", e));
             }
+            buf
+        } else {
+            data
+        };
 
-            self.data = buf;
-        }
-        Ok(Some(&self.data))
+        Ok(DecodedFrame {
+            data,
+            compressed_len,
+            assembly_time,
+        })
     }
 }
 
 impl JPEGHeader {
-    pub fn new(data: &[u8]) -> Self {
+    #[cfg(test)]
+    pub fn new(id: i32, length: i32, offset: i32, gzip: bool) -> Self {
         Self {
-            // label: i32::from_le_bytes(data[0..4].try_into().unwrap()),
-            id: i32::from_le_bytes(data[4..8].try_into().unwrap()),
-            length: i32::from_le_bytes(data[8..12].try_into().unwrap()),
-            offset: i32::from_le_bytes(data[12..16].try_into().unwrap()),
+            label: 0,
+            id,
+            length,
+            offset,
+            gzip,
+            color_reduction: 0,
+        }
+    }
+
+    /// Reads a header from the first [`JPEG_HEADER_LEN`] bytes of `data`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, JPEGHeaderError> {
+        let data: &[u8; JPEG_HEADER_LEN] = data
+            .get(..JPEG_HEADER_LEN)
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(JPEGHeaderError::Truncated { actual: data.len() })?;
+
+        let read_i32 =
+            |at: usize| i32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+
+        let header = Self {
+            label: read_i32(0),
+            id: read_i32(4),
+            length: read_i32(8),
+            offset: read_i32(12),
             gzip: data[16] != 0,
-            // color_reduction: data[17],
+            color_reduction: data[17],
+        };
+
+        if header.length < 0 {
+            return Err(JPEGHeaderError::NegativeLength(header.length));
+        }
+        if header.length > MAX_JPEG_FRAME_LEN {
+            return Err(JPEGHeaderError::LengthTooLarge(header.length));
+        }
+        if header.offset < 0 {
+            return Err(JPEGHeaderError::NegativeOffset(header.offset));
         }
+
+        Ok(header)
+    }
+
+    /// Checks that a chunk of `len` bytes at this header's offset stays inside the frame.
+    pub fn check_chunk(&self, len: usize) -> Result<(), JPEGHeaderError> {
+        let end_at = (self.offset as usize).checked_add(len);
+        match end_at {
+            Some(end_at) if self.offset >= 0 && end_at <= self.length.max(0) as usize => Ok(()),
+            _ => Err(JPEGHeaderError::ChunkOutOfBounds {
+                offset: self.offset,
+                len,
+                length: self.length,
+            }),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; JPEG_HEADER_LEN] {
+        let mut bytes = [0; JPEG_HEADER_LEN];
+        bytes[0..4].copy_from_slice(&self.label.to_le_bytes());
+        bytes[4..8].copy_from_slice(&self.id.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.length.to_le_bytes());
+        bytes[12..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16] = self.gzip as u8;
+        bytes[17] = self.color_reduction;
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends the start of a 4 byte frame, `true` if that completed it.
+    fn chunk(decoder: &mut JPEGDecoder, id: i32, data: &[u8], now: Instant) -> bool {
+        let header = JPEGHeader::new(id, 4, 0, false);
+        decoder.append_data_at(header, data, now).unwrap().is_some()
+    }
+
+    #[test]
+    fn completes_a_frame_from_its_chunks() {
+        let mut decoder = JPEGDecoder::new();
+        let header = JPEGHeader::new(1, 4, 2, false);
+        assert!(decoder.append_data(header, b"cd").unwrap().is_none());
+
+        let frame = decoder
+            .append_data(JPEGHeader::new(1, 4, 0, false), b"ab")
+            .unwrap()
+            .unwrap();
+        assert_eq!(frame.data, b"abcd");
+        assert_eq!(frame.compressed_len, 4);
+        assert_eq!(decoder.stats().frames_completed, 1);
+    }
+
+    #[test]
+    fn incomplete_frames_expire() {
+        let mut decoder = JPEGDecoder::new();
+        chunk(&mut decoder, 1, b"ab", Instant::now());
+        let started = decoder.frames[&1].chunks.started_at();
+
+        decoder.expire_frames(started + FRAME_TIMEOUT / 2);
+        assert_eq!(decoder.stats().frames_dropped, 0);
+        decoder.expire_frames(started + FRAME_TIMEOUT);
+        assert_eq!(decoder.stats().frames_dropped, 1);
+        assert!(decoder.frames.is_empty());
+    }
+
+    #[test]
+    fn oldest_frame_is_evicted_beyond_the_limit() {
+        let mut decoder = JPEGDecoder::new();
+        for id in 0..=MAX_PENDING_FRAMES as i32 {
+            chunk(&mut decoder, id, b"ab", Instant::now());
+            // frames are ordered by when their first chunk arrived
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(decoder.frames.len(), MAX_PENDING_FRAMES);
+        assert!(!decoder.frames.contains_key(&0));
+        assert_eq!(decoder.stats().frames_dropped, 1);
+    }
+
+    #[test]
+    fn chunks_of_older_ids_are_late() {
+        let now = Instant::now();
+        let mut decoder = JPEGDecoder::new();
+        chunk(&mut decoder, 100, b"abcd", now);
+
+        assert!(!chunk(&mut decoder, 100, b"ab", now));
+        assert!(!chunk(&mut decoder, 101 - STALE_ID_WINDOW, b"ab", now));
+        assert_eq!(decoder.stats().late_chunks, 2);
+        assert!(decoder.frames.is_empty());
+
+        // too far back to be late, a restarted client
+        chunk(&mut decoder, 100 - STALE_ID_WINDOW, b"ab", now);
+        assert_eq!(decoder.stats().late_chunks, 2);
+        assert_eq!(decoder.frames.len(), 1);
+    }
+
+    #[test]
+    fn completing_a_frame_drops_older_pending_ones() {
+        let now = Instant::now();
+        let mut decoder = JPEGDecoder::new();
+        chunk(&mut decoder, 3, b"ab", now);
+        chunk(&mut decoder, 5, b"ab", now);
+
+        assert!(chunk(&mut decoder, 4, b"abcd", now));
+        assert_eq!(decoder.stats().frames_dropped, 1);
+        assert!(decoder.frames.contains_key(&5));
+        assert!(!decoder.frames.contains_key(&3));
+    }
+
+    #[test]
+    fn stream_starting_over_is_accepted_after_a_pause() {
+        let now = Instant::now();
+        let mut decoder = JPEGDecoder::new();
+        chunk(&mut decoder, 30, b"abcd", now);
+
+        assert!(!chunk(&mut decoder, 0, b"abcd", now));
+        assert!(chunk(&mut decoder, 0, b"abcd", now + FRAME_TIMEOUT));
+        assert_eq!(decoder.stats().late_chunks, 1);
+        assert_eq!(decoder.stats().frames_completed, 2);
     }
 }
@@ -1,56 +1,412 @@
-use crate::fm_network::jpeg_decoder::JPEGHeader;
+use std::fmt::Display;
 
+use serde::Serialize;
+
+use crate::fm_network::history_decoder::{
+    HistoryChunkHeader, HistoryHeaderError, HISTORY_HEADER_LEN,
+};
+use crate::fm_network::jpeg_decoder::{JPEGHeader, JPEGHeaderError, JPEG_HEADER_LEN};
+
+/// Version written into the second byte of every packet (except heartbeat).
+///
+/// Older clients use that byte as a target type the server never cared about, so it is only
+/// checked for the kinds added along with the version, see [`PacketKind::is_versioned`].
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Size of the `[kind, version]` prefix shared by every non-heartbeat packet.
+const META_LEN: usize = 2;
+
+/// Size of the little-endian sequence number leading command and ack bodies.
+const SEQ_LEN: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub enum PacketKind {
+    Jpeg = 0,
+    String = 1,
+    PlayHistory = 2,
+    Hello = 3,
+    Command = 4,
+    Ack = 5,
+    // 6 was the history request sent to headsets, since replaced by the `export` command.
+    // Headsets built against it still understand it, so the value is reserved, never reused.
+    HistoryChunk = 7,
+}
+
+impl PacketKind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Self::Jpeg),
+            1 => Some(Self::String),
+            2 => Some(Self::PlayHistory),
+            3 => Some(Self::Hello),
+            4 => Some(Self::Command),
+            5 => Some(Self::Ack),
+            7 => Some(Self::HistoryChunk),
+            _ => None,
+        }
+    }
+
+    /// Whether packets of this kind carry [`PROTOCOL_VERSION`] in their second byte.
+    fn is_versioned(self) -> bool {
+        !matches!(self, Self::Jpeg | Self::String | Self::PlayHistory)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub enum PacketError {
+    Empty,
+    UnknownKind(u8),
+    UnsupportedVersion(u8),
+    Truncated {
+        kind: PacketKind,
+        expected: usize,
+        actual: usize,
+    },
+    InvalidJpegHeader(JPEGHeaderError),
+    InvalidHistoryHeader(HistoryHeaderError),
+}
+
+impl Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => write!(f, "empty datagram"),
+            Self::UnknownKind(kind) => write!(f, "unknown packet kind {}", kind),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported protocol version {} (max {})",
+                version, PROTOCOL_VERSION
+            ),
+            Self::Truncated {
+                kind,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "truncated {:?} packet, expected at least {} bytes, got {}",
+                kind, expected, actual
+            ),
+            Self::InvalidJpegHeader(e) => write!(f, "invalid JPEG header, {}", e),
+            Self::InvalidHistoryHeader(e) => write!(f, "invalid history header, {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum FMPacket {
-    Unknown,
     Heartbeat,
-    StringPacket { data: String },
-    JPEGPacket { header: JPEGHeader, data: Vec<u8> },
-    PlayHistoryPacket { json: String },
+    StringPacket {
+        data: String,
+    },
+    JPEGPacket {
+        header: JPEGHeader,
+        data: Vec<u8>,
+    },
+    PlayHistoryPacket {
+        json: String,
+    },
+    /// Sent by a headset to announce its stable device id.
+    Hello {
+        device_id: String,
+    },
+    /// Text command the headset must confirm with an [`FMPacket::Ack`] carrying the same `seq`.
+    Command {
+        seq: u32,
+        data: String,
+    },
+    Ack {
+        seq: u32,
+    },
+    /// One piece of a play history too large for a single datagram.
+    HistoryChunk {
+        header: HistoryChunkHeader,
+        data: Vec<u8>,
+    },
 }
 
 impl FMPacket {
-    pub fn new(raw_data: &[u8]) -> Self {
-        if raw_data.len() == 1 {
-            return Self::Heartbeat;
+    pub fn decode(raw_data: &[u8]) -> Result<Self, PacketError> {
+        match raw_data.len() {
+            0 => return Err(PacketError::Empty),
+            1 => return Ok(Self::Heartbeat),
+            _ => {}
         }
 
-        if raw_data.len() <= 2 {
-            return Self::Unknown;
+        let kind =
+            PacketKind::from_byte(raw_data[0]).ok_or(PacketError::UnknownKind(raw_data[0]))?;
+
+        let version = raw_data[1];
+        if kind.is_versioned() && version > PROTOCOL_VERSION {
+            return Err(PacketError::UnsupportedVersion(version));
         }
 
-        match &raw_data[0] {
-            0 => Self::JPEGPacket {
-                header: JPEGHeader::new(&raw_data[2..20]),
-                data: raw_data[20..].to_vec(),
-            },
-            1 => Self::decode_string(&raw_data[2..]),
-            2 => Self::decode_play_history(&raw_data[2..]),
-            _ => Self::Unknown,
+        let body = &raw_data[META_LEN..];
+        match kind {
+            PacketKind::Jpeg => Self::decode_jpeg(body),
+            PacketKind::String => Ok(Self::decode_string(body)),
+            PacketKind::PlayHistory => Ok(Self::decode_play_history(body)),
+            PacketKind::Hello => Ok(Self::decode_hello(body)),
+            PacketKind::Command => {
+                let seq = Self::read_seq(kind, body)?;
+                Ok(Self::Command {
+                    seq,
+                    data: String::from_utf8_lossy(&body[SEQ_LEN..]).into_owned(),
+                })
+            }
+            PacketKind::Ack => Ok(Self::Ack {
+                seq: Self::read_seq(kind, body)?,
+            }),
+            PacketKind::HistoryChunk => Self::decode_history_chunk(body),
+        }
+    }
+
+    fn decode_jpeg(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < JPEG_HEADER_LEN {
+            return Err(PacketError::Truncated {
+                kind: PacketKind::Jpeg,
+                expected: META_LEN + JPEG_HEADER_LEN,
+                actual: META_LEN + bytes.len(),
+            });
         }
+
+        let header = JPEGHeader::from_bytes(bytes).map_err(PacketError::InvalidJpegHeader)?;
+        let data = &bytes[JPEG_HEADER_LEN..];
+        header
+            .check_chunk(data.len())
+            .map_err(PacketError::InvalidJpegHeader)?;
+
+        Ok(Self::JPEGPacket {
+            header,
+            data: data.to_vec(),
+        })
+    }
+
+    fn decode_history_chunk(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() < HISTORY_HEADER_LEN {
+            return Err(PacketError::Truncated {
+                kind: PacketKind::HistoryChunk,
+                expected: META_LEN + HISTORY_HEADER_LEN,
+                actual: META_LEN + bytes.len(),
+            });
+        }
+
+        let header =
+            HistoryChunkHeader::from_bytes(bytes).map_err(PacketError::InvalidHistoryHeader)?;
+        let data = &bytes[HISTORY_HEADER_LEN..];
+        header
+            .check_chunk(data.len())
+            .map_err(PacketError::InvalidHistoryHeader)?;
+
+        Ok(Self::HistoryChunk {
+            header,
+            data: data.to_vec(),
+        })
     }
 
     fn decode_string(bytes: &[u8]) -> Self {
-        let data = String::from_utf8_lossy(&bytes[0..]).into_owned();
+        let data = String::from_utf8_lossy(bytes).into_owned();
         Self::StringPacket { data }
     }
 
     fn decode_play_history(bytes: &[u8]) -> Self {
-        let json = String::from_utf8_lossy(&bytes[0..]).into_owned();
+        let json = String::from_utf8_lossy(bytes).into_owned();
         Self::PlayHistoryPacket { json }
     }
 
-    pub(crate) fn to_bytes(&self) -> Option<Vec<u8>> {
+    fn read_seq(kind: PacketKind, bytes: &[u8]) -> Result<u32, PacketError> {
+        match bytes.get(..SEQ_LEN) {
+            Some(seq) => Ok(u32::from_le_bytes([seq[0], seq[1], seq[2], seq[3]])),
+            None => Err(PacketError::Truncated {
+                kind,
+                expected: META_LEN + SEQ_LEN,
+                actual: META_LEN + bytes.len(),
+            }),
+        }
+    }
+
+    fn decode_hello(bytes: &[u8]) -> Self {
+        let device_id = String::from_utf8_lossy(bytes).trim().to_owned();
+        Self::Hello { device_id }
+    }
+
+    pub fn kind(&self) -> Option<PacketKind> {
+        match self {
+            Self::Heartbeat => None,
+            Self::StringPacket { .. } => Some(PacketKind::String),
+            Self::JPEGPacket { .. } => Some(PacketKind::Jpeg),
+            Self::PlayHistoryPacket { .. } => Some(PacketKind::PlayHistory),
+            Self::Hello { .. } => Some(PacketKind::Hello),
+            Self::Command { .. } => Some(PacketKind::Command),
+            Self::Ack { .. } => Some(PacketKind::Ack),
+            Self::HistoryChunk { .. } => Some(PacketKind::HistoryChunk),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let kind = match self.kind() {
+            Some(kind) => kind,
+            None => return vec![1], // heartbeat is a single byte
+        };
+
+        let mut bytes = vec![kind as u8, PROTOCOL_VERSION];
         match self {
-            Self::Unknown => None,
-            Self::Heartbeat => Some(vec![1]),
-            Self::StringPacket { data } => {
-                let mut bytes = vec![];
-                bytes.push(1); // meta byte index 0 => string = 1
-                bytes.push(0); // target type, not care
+            Self::Heartbeat => {}
+            Self::StringPacket { data } => bytes.extend_from_slice(data.as_bytes()),
+            Self::JPEGPacket { header, data } => {
+                bytes.extend_from_slice(&header.to_bytes());
+                bytes.extend_from_slice(data);
+            }
+            Self::PlayHistoryPacket { json } => bytes.extend_from_slice(json.as_bytes()),
+            Self::Hello { device_id } => bytes.extend_from_slice(device_id.as_bytes()),
+            Self::Command { seq, data } => {
+                bytes.extend_from_slice(&seq.to_le_bytes());
                 bytes.extend_from_slice(data.as_bytes());
-                Some(bytes)
             }
-            _ => None,
+            Self::Ack { seq } => bytes.extend_from_slice(&seq.to_le_bytes()),
+            Self::HistoryChunk { header, data } => {
+                bytes.extend_from_slice(&header.to_bytes());
+                bytes.extend_from_slice(data);
+            }
         }
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(packet: FMPacket) {
+        assert_eq!(FMPacket::decode(&packet.encode()), Ok(packet));
+    }
+
+    #[test]
+    fn every_variant_round_trips() {
+        round_trip(FMPacket::Heartbeat);
+        round_trip(FMPacket::StringPacket {
+            data: "start mission".into(),
+        });
+        round_trip(FMPacket::JPEGPacket {
+            header: JPEGHeader::new(7, 6, 2, true),
+            data: vec![1, 2, 3, 4],
+        });
+        round_trip(FMPacket::PlayHistoryPacket {
+            json: r#"{"userId":"u1","missionDatas":[]}"#.into(),
+        });
+        round_trip(FMPacket::Hello {
+            device_id: "quest-01".into(),
+        });
+        round_trip(FMPacket::Command {
+            seq: 0x0102_0304,
+            data: "export".into(),
+        });
+        round_trip(FMPacket::Ack { seq: u32::MAX });
+        round_trip(FMPacket::HistoryChunk {
+            header: HistoryChunkHeader {
+                id: 3,
+                length: 10,
+                offset: 4,
+                crc32: 0xdead_beef,
+                gzip: false,
+            },
+            data: vec![9; 6],
+        });
+    }
+
+    #[test]
+    fn legacy_kinds_ignore_the_second_byte() {
+        let mut bytes = FMPacket::StringPacket { data: "hi".into() }.encode();
+        bytes[1] = 0xff;
+        assert_eq!(
+            FMPacket::decode(&bytes),
+            Ok(FMPacket::StringPacket { data: "hi".into() })
+        );
+    }
+
+    #[test]
+    fn newer_kinds_reject_a_future_version() {
+        let mut bytes = FMPacket::Ack { seq: 1 }.encode();
+        bytes[1] = PROTOCOL_VERSION + 1;
+        assert_eq!(
+            FMPacket::decode(&bytes),
+            Err(PacketError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+    }
+
+    /// A JPEG datagram with a hand-written header, so invalid values can be sent.
+    fn jpeg_datagram(length: i32, offset: i32, data: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![PacketKind::Jpeg as u8, 0];
+        for value in [0, 1, length, offset] {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+        bytes.extend_from_slice(&[0, 0]);
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn short_jpeg_datagrams_are_truncated() {
+        for len in 3..META_LEN + JPEG_HEADER_LEN {
+            let mut bytes = vec![0; len];
+            bytes[0] = PacketKind::Jpeg as u8;
+            assert_eq!(
+                FMPacket::decode(&bytes),
+                Err(PacketError::Truncated {
+                    kind: PacketKind::Jpeg,
+                    expected: META_LEN + JPEG_HEADER_LEN,
+                    actual: len,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn negative_jpeg_length_or_offset_is_rejected() {
+        assert_eq!(
+            FMPacket::decode(&jpeg_datagram(-1, 0, &[])),
+            Err(PacketError::InvalidJpegHeader(
+                JPEGHeaderError::NegativeLength(-1)
+            ))
+        );
+        assert_eq!(
+            FMPacket::decode(&jpeg_datagram(8, -4, &[1])),
+            Err(PacketError::InvalidJpegHeader(
+                JPEGHeaderError::NegativeOffset(-4)
+            ))
+        );
+    }
+
+    #[test]
+    fn jpeg_chunk_past_the_frame_is_rejected() {
+        assert_eq!(
+            FMPacket::decode(&jpeg_datagram(8, 6, &[1, 2, 3])),
+            Err(PacketError::InvalidJpegHeader(
+                JPEGHeaderError::ChunkOutOfBounds {
+                    offset: 6,
+                    len: 3,
+                    length: 8,
+                }
+            ))
+        );
+        assert!(FMPacket::decode(&jpeg_datagram(i32::MAX, i32::MAX, &[1])).is_err());
+    }
+
+    #[test]
+    fn rejects_empty_unknown_and_truncated() {
+        assert_eq!(FMPacket::decode(&[]), Err(PacketError::Empty));
+        assert_eq!(
+            FMPacket::decode(&[42, 1]),
+            Err(PacketError::UnknownKind(42))
+        );
+        assert_eq!(FMPacket::decode(&[6, 1]), Err(PacketError::UnknownKind(6)));
+        assert_eq!(
+            FMPacket::decode(&[PacketKind::Ack as u8, 1, 0]),
+            Err(PacketError::Truncated {
+                kind: PacketKind::Ack,
+                expected: 6,
+                actual: 3,
+            })
+        );
     }
 }
@@ -0,0 +1,228 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Deserializer, Serialize};
+
+/// A headset known to the controller, keyed by the id it sends in its hello packet.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DeviceRecord {
+    pub device_id: String,
+    pub name: String,
+    pub station: Option<u32>,
+    /// Room or class the headset belongs to, addressed by `Target::Group`.
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub notes: String,
+    pub last_address: Option<SocketAddr>,
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+/// Changes to a [`DeviceRecord`], fields left out keep their value.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct DeviceUpdate {
+    pub name: Option<String>,
+    /// `null` clears the station.
+    #[serde(default, deserialize_with = "present")]
+    pub station: Option<Option<u32>>,
+    /// `null` or an empty string clears the group.
+    #[serde(default, deserialize_with = "present")]
+    pub group: Option<Option<String>>,
+    pub notes: Option<String>,
+}
+
+pub(crate) struct DeviceRegistry {
+    path: PathBuf,
+    devices: HashMap<String, DeviceRecord>,
+}
+
+impl DeviceRecord {
+    fn new(device_id: &str, addr: SocketAddr) -> Self {
+        let now = unix_now();
+        Self {
+            device_id: device_id.into(),
+            name: device_id.into(),
+            station: None,
+            group: None,
+            notes: String::new(),
+            last_address: Some(addr),
+            first_seen: now,
+            last_seen: now,
+        }
+    }
+}
+
+impl DeviceRegistry {
+    /// Reads the registry file, starting empty if it is missing or unreadable.
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let devices = match std::fs::read_to_string(&path) {
+            Ok(json) => match serde_json::from_str::<Vec<DeviceRecord>>(&json) {
+                Ok(records) => records
+                    .into_iter()
+                    .map(|record| (record.device_id.clone(), record))
+                    .collect(),
+                Err(e) => {
+                    eprintln!("Error parsing device registry {}: {}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        Self { path, devices }
+    }
+
+    pub async fn save(&self) {
+        let json = match serde_json::to_string_pretty(&self.list()) {
+            Ok(json) => json,
+            Err(e) => {
+                eprintln!("Error serializing device registry: {}", e);
+                return;
+            }
+        };
+
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await.ok();
+        }
+        if let Err(e) = tokio::fs::write(&self.path, json).await {
+            eprintln!(
+                "Error writing device registry {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+
+    pub fn list(&self) -> Vec<DeviceRecord> {
+        let mut records: Vec<DeviceRecord> = self.devices.values().cloned().collect();
+        records.sort_by(|a, b| a.station.cmp(&b.station).then(a.name.cmp(&b.name)));
+        records
+    }
+
+    /// Ties `device_id` to the address it was last seen at, registering it on first contact.
+    ///
+    /// Also returns whether the record needs saving, i.e. the device or its address is new.
+    pub fn bind(&mut self, device_id: &str, addr: SocketAddr) -> (DeviceRecord, bool) {
+        let mut changed = false;
+        let record = self.devices.entry(device_id.into()).or_insert_with(|| {
+            changed = true;
+            DeviceRecord::new(device_id, addr)
+        });
+
+        changed |= record.last_address != Some(addr);
+        record.last_address = Some(addr);
+        record.last_seen = unix_now();
+        (record.clone(), changed)
+    }
+
+    pub fn update(&mut self, device_id: &str, update: DeviceUpdate) -> Option<DeviceRecord> {
+        let record = self.devices.get_mut(device_id)?;
+        if let Some(name) = update.name {
+            record.name = name;
+        }
+        if let Some(station) = update.station {
+            record.station = station;
+        }
+        if let Some(group) = update.group {
+            record.group = group.filter(|group| !group.trim().is_empty());
+        }
+        if let Some(notes) = update.notes {
+            record.notes = notes;
+        }
+        Some(record.clone())
+    }
+
+    pub fn get(&self, device_id: &str) -> Option<&DeviceRecord> {
+        self.devices.get(device_id)
+    }
+
+    pub fn forget(&mut self, device_id: &str) -> bool {
+        self.devices.remove(device_id).is_some()
+    }
+}
+
+/// Tells an explicit `null` (`Some(None)`) apart from a missing field (`None`).
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry() -> DeviceRegistry {
+        let mut registry = DeviceRegistry {
+            path: PathBuf::new(),
+            devices: HashMap::new(),
+        };
+        registry.bind("quest-01", "10.0.0.5:9000".parse().unwrap());
+        registry
+            .update(
+                "quest-01",
+                serde_json::from_str(r#"{"station":3,"group":"A","notes":"left strap"}"#).unwrap(),
+            )
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn update_keeps_missing_fields() {
+        let mut registry = registry();
+        let record = registry
+            .update(
+                "quest-01",
+                serde_json::from_str(r#"{"name":"Bay 3"}"#).unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(record.name, "Bay 3");
+        assert_eq!(record.station, Some(3));
+        assert_eq!(record.group.as_deref(), Some("A"));
+        assert_eq!(record.notes, "left strap");
+    }
+
+    #[test]
+    fn update_clears_null_fields() {
+        let mut registry = registry();
+        let record = registry
+            .update(
+                "quest-01",
+                serde_json::from_str(r#"{"station":null,"group":""}"#).unwrap(),
+            )
+            .unwrap();
+
+        assert_eq!(record.station, None);
+        assert_eq!(record.group, None);
+        assert_eq!(record.notes, "left strap");
+    }
+
+    #[test]
+    fn bind_reports_changes_only() {
+        let mut registry = registry();
+        let addr = "10.0.0.5:9000".parse().unwrap();
+        assert!(!registry.bind("quest-01", addr).1);
+        assert!(
+            registry
+                .bind("quest-01", "10.0.0.6:9000".parse().unwrap())
+                .1
+        );
+        assert!(registry.bind("quest-02", addr).1);
+    }
+}
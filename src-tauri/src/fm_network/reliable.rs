@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    time::Instant,
+};
+
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::config::RetryPolicy;
+use crate::fm_network::{error::NetworkError, packet::FMPacket, Shared};
+
+/// Commands sent with [`FMPacket::Command`] that still wait for their ack.
+pub(crate) struct PendingCommands {
+    next_seq: u32,
+    waiting: HashMap<u32, (IpAddr, oneshot::Sender<()>)>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum CommandOutcome {
+    /// Handed to the socket without asking for an ack.
+    Sent,
+    Acked {
+        attempts: u32,
+        rtt_ms: u64,
+    },
+    TimedOut {
+        attempts: u32,
+    },
+    Failed {
+        reason: String,
+    },
+}
+
+impl PendingCommands {
+    pub fn new() -> Self {
+        Self {
+            next_seq: 0,
+            waiting: HashMap::new(),
+        }
+    }
+
+    fn register(&mut self, ip: IpAddr) -> (u32, oneshot::Receiver<()>) {
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let seq = self.next_seq;
+        let (sender, receiver) = oneshot::channel();
+        self.waiting.insert(seq, (ip, sender));
+        (seq, receiver)
+    }
+
+    /// Resolves the command `seq` if the ack came from the headset it was sent to.
+    pub fn acknowledge(&mut self, seq: u32, from: IpAddr) -> bool {
+        match self.waiting.get(&seq) {
+            Some((ip, _)) if *ip == from => {}
+            _ => return false,
+        }
+
+        match self.waiting.remove(&seq) {
+            Some((_, sender)) => sender.send(()).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Drops every waiting command, their senders resolve as failed.
+    pub fn clear(&mut self) {
+        self.waiting.clear();
+    }
+}
+
+/// Sends `data` to `addr` and resends it with backoff until the headset acks it.
+pub(crate) async fn send_reliable(
+    shared: &Shared,
+    addr: SocketAddr,
+    data: String,
+    policy: &RetryPolicy,
+) -> CommandOutcome {
+    let (seq, mut acked) = shared.commands.lock().await.register(addr.ip());
+    let send = |packet| shared.send(addr.into(), packet);
+    let outcome = retry(send, seq, data, policy, &mut acked).await;
+
+    // already gone if acked, otherwise stop waiting for a late ack
+    shared.commands.lock().await.waiting.remove(&seq);
+    outcome
+}
+
+async fn retry<F, Fut>(
+    send: F,
+    seq: u32,
+    data: String,
+    policy: &RetryPolicy,
+    acked: &mut oneshot::Receiver<()>,
+) -> CommandOutcome
+where
+    F: Fn(FMPacket) -> Fut,
+    Fut: Future<Output = Result<(), NetworkError>>,
+{
+    let started_at = Instant::now();
+    let mut timeout = policy.initial_timeout();
+
+    for attempt in 1..=policy.max_attempts {
+        let packet = FMPacket::Command {
+            seq,
+            data: data.clone(),
+        };
+        if let Err(e) = send(packet).await {
+            return CommandOutcome::Failed {
+                reason: e.to_string(),
+            };
+        }
+
+        match tokio::time::timeout(timeout, &mut *acked).await {
+            Ok(Ok(())) => {
+                return CommandOutcome::Acked {
+                    attempts: attempt,
+                    rtt_ms: started_at.elapsed().as_millis() as u64,
+                }
+            }
+            Ok(Err(_)) => {
+                return CommandOutcome::Failed {
+                    reason: "network stopped before the command was acknowledged".into(),
+                }
+            }
+            Err(_) => timeout = policy.next_timeout(timeout),
+        }
+    }
+
+    CommandOutcome::TimedOut {
+        attempts: policy.max_attempts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+
+    use super::*;
+
+    fn quick_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_timeout_ms: 1,
+            backoff_factor: 2.0,
+            max_timeout_ms: 2,
+        }
+    }
+
+    #[test]
+    fn timeouts_back_off_up_to_the_max() {
+        let policy = RetryPolicy::default();
+        let mut timeout = policy.initial_timeout();
+        let mut timeouts = vec![timeout];
+        for _ in 0..4 {
+            timeout = policy.next_timeout(timeout);
+            timeouts.push(timeout);
+        }
+
+        let expected: Vec<Duration> = [500, 1000, 2000, 4000, 4000]
+            .into_iter()
+            .map(Duration::from_millis)
+            .collect();
+        assert_eq!(timeouts, expected);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_last_attempt() {
+        let sent = AtomicU32::new(0);
+        let send = |_| {
+            sent.fetch_add(1, Ordering::SeqCst);
+            async { Ok(()) }
+        };
+        let (_ack, mut acked) = oneshot::channel();
+
+        let outcome = retry(send, 1, "recenter".into(), &quick_policy(), &mut acked).await;
+        assert!(matches!(outcome, CommandOutcome::TimedOut { attempts: 3 }));
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn stops_resending_once_acked() {
+        let sent = AtomicU32::new(0);
+        let send = |_| {
+            sent.fetch_add(1, Ordering::SeqCst);
+            async { Ok(()) }
+        };
+        let (ack, mut acked) = oneshot::channel();
+        ack.send(()).unwrap();
+
+        let outcome = retry(send, 1, "recenter".into(), &quick_policy(), &mut acked).await;
+        assert!(matches!(outcome, CommandOutcome::Acked { attempts: 1, .. }));
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn fails_when_the_send_fails() {
+        let send = |_| async { Err(NetworkError::NotRunning) };
+        let (_ack, mut acked) = oneshot::channel();
+
+        let outcome = retry(send, 1, "recenter".into(), &quick_policy(), &mut acked).await;
+        assert!(matches!(outcome, CommandOutcome::Failed { .. }));
+    }
+
+    #[test]
+    fn only_the_addressed_headset_can_ack() {
+        let mut pending = PendingCommands::new();
+        let headset: IpAddr = "10.0.0.2".parse().unwrap();
+        let (seq, mut acked) = pending.register(headset);
+
+        assert!(!pending.acknowledge(seq, "10.0.0.3".parse().unwrap()));
+        assert!(acked.try_recv().is_err());
+        assert!(pending.acknowledge(seq, headset));
+        assert!(acked.try_recv().is_ok());
+        assert!(!pending.acknowledge(seq, headset));
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    collections::VecDeque,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::fm_network::jpeg_decoder::{DecodedFrame, DecoderStats};
+
+/// Window used for the rolling fps and bitrate.
+const RATE_WINDOW: Duration = Duration::from_secs(2);
+
+pub(crate) struct StreamStatsTracker {
+    addr: SocketAddr,
+    started_at: Instant,
+    decoder: DecoderStats,
+    chunks_received: u64,
+    bytes_received: u64,
+    compressed_bytes: u64,
+    decompressed_bytes: u64,
+    total_assembly_time: Duration,
+    recent_chunks: VecDeque<(Instant, usize)>,
+    recent_frames: VecDeque<Instant>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StreamStats {
+    addr: SocketAddr,
+    uptime_secs: f64,
+    frames_completed: u64,
+    frames_abandoned: u64,
+    duplicate_chunks: u64,
+    late_chunks: u64,
+    chunks_received: u64,
+    bytes_received: u64,
+    compressed_bytes: u64,
+    decompressed_bytes: u64,
+    fps: f64,
+    bitrate_kbps: f64,
+    drop_rate: f64,
+    avg_assembly_ms: f64,
+}
+
+impl StreamStatsTracker {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            started_at: Instant::now(),
+            decoder: DecoderStats::default(),
+            chunks_received: 0,
+            bytes_received: 0,
+            compressed_bytes: 0,
+            decompressed_bytes: 0,
+            total_assembly_time: Duration::ZERO,
+            recent_chunks: VecDeque::new(),
+            recent_frames: VecDeque::new(),
+        }
+    }
+
+    pub fn record_chunk(&mut self, len: usize) {
+        let now = Instant::now();
+        self.chunks_received += 1;
+        self.bytes_received += len as u64;
+        self.recent_chunks.push_back((now, len));
+        self.trim(now);
+    }
+
+    pub fn record_frame(&mut self, frame: &DecodedFrame) {
+        let now = Instant::now();
+        self.compressed_bytes += frame.compressed_len as u64;
+        self.decompressed_bytes += frame.data.len() as u64;
+        self.total_assembly_time += frame.assembly_time;
+        self.recent_frames.push_back(now);
+        self.trim(now);
+    }
+
+    /// Copies the reassembly counters kept by the stream's [`JPEGDecoder`](crate::fm_network::jpeg_decoder::JPEGDecoder).
+    pub fn update_decoder(&mut self, stats: DecoderStats) {
+        self.decoder = stats;
+    }
+
+    pub fn snapshot(&mut self) -> StreamStats {
+        let now = Instant::now();
+        self.trim(now);
+
+        let window = RATE_WINDOW
+            .min(now.duration_since(self.started_at))
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        let recent_bytes: usize = self.recent_chunks.iter().map(|(_, len)| len).sum();
+
+        let completed = self.decoder.frames_completed;
+        let abandoned = self.decoder.frames_dropped;
+        let finished = completed + abandoned;
+
+        StreamStats {
+            addr: self.addr,
+            uptime_secs: now.duration_since(self.started_at).as_secs_f64(),
+            frames_completed: completed,
+            frames_abandoned: abandoned,
+            duplicate_chunks: self.decoder.duplicate_chunks,
+            late_chunks: self.decoder.late_chunks,
+            chunks_received: self.chunks_received,
+            bytes_received: self.bytes_received,
+            compressed_bytes: self.compressed_bytes,
+            decompressed_bytes: self.decompressed_bytes,
+            fps: self.recent_frames.len() as f64 / window,
+            bitrate_kbps: recent_bytes as f64 * 8.0 / 1000.0 / window,
+            drop_rate: if finished == 0 {
+                0.0
+            } else {
+                abandoned as f64 / finished as f64
+            },
+            avg_assembly_ms: if completed == 0 {
+                0.0
+            } else {
+                self.total_assembly_time.as_secs_f64() * 1000.0 / completed as f64
+            },
+        }
+    }
+
+    fn trim(&mut self, now: Instant) {
+        while let Some((at, _)) = self.recent_chunks.front() {
+            if now.duration_since(*at) <= RATE_WINDOW {
+                break;
+            }
+            self.recent_chunks.pop_front();
+        }
+
+        while let Some(at) = self.recent_frames.front() {
+            if now.duration_since(*at) <= RATE_WINDOW {
+                break;
+            }
+            self.recent_frames.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracker() -> StreamStatsTracker {
+        StreamStatsTracker::new("127.0.0.1:9000".parse().unwrap())
+    }
+
+    fn frame(len: usize, compressed_len: usize, assembly_ms: u64) -> DecodedFrame {
+        DecodedFrame {
+            data: vec![0; len],
+            compressed_len,
+            assembly_time: Duration::from_millis(assembly_ms),
+        }
+    }
+
+    #[test]
+    fn empty_stream_reports_zero_rates() {
+        let stats = tracker().snapshot();
+        assert_eq!(stats.frames_completed, 0);
+        assert_eq!(stats.drop_rate, 0.0);
+        assert_eq!(stats.avg_assembly_ms, 0.0);
+        assert_eq!(stats.fps, 0.0);
+        assert_eq!(stats.bitrate_kbps, 0.0);
+    }
+
+    #[test]
+    fn counts_chunks_and_frames() {
+        let mut tracker = tracker();
+        tracker.record_chunk(100);
+        tracker.record_chunk(50);
+        tracker.record_frame(&frame(400, 150, 10));
+        tracker.record_frame(&frame(600, 200, 30));
+        tracker.update_decoder(DecoderStats {
+            frames_completed: 2,
+            ..Default::default()
+        });
+
+        let stats = tracker.snapshot();
+        assert_eq!(stats.chunks_received, 2);
+        assert_eq!(stats.bytes_received, 150);
+        assert_eq!(stats.compressed_bytes, 350);
+        assert_eq!(stats.decompressed_bytes, 1000);
+        assert_eq!(stats.avg_assembly_ms, 20.0);
+        assert!(stats.fps > 0.0);
+        assert!(stats.bitrate_kbps > 0.0);
+    }
+
+    #[test]
+    fn drop_rate_covers_completed_and_abandoned_frames() {
+        let mut tracker = tracker();
+        tracker.update_decoder(DecoderStats {
+            frames_completed: 3,
+            frames_dropped: 1,
+            duplicate_chunks: 2,
+            late_chunks: 5,
+        });
+
+        let stats = tracker.snapshot();
+        assert_eq!(stats.frames_abandoned, 1);
+        assert_eq!(stats.drop_rate, 0.25);
+        assert_eq!(stats.duplicate_chunks, 2);
+        assert_eq!(stats.late_chunks, 5);
+    }
+
+    #[test]
+    fn rates_only_cover_the_recent_window() {
+        let mut tracker = tracker();
+        tracker.record_chunk(100);
+        tracker.record_frame(&frame(10, 10, 1));
+
+        let later = Instant::now() + RATE_WINDOW + Duration::from_millis(1);
+        tracker.trim(later);
+        assert!(tracker.recent_chunks.is_empty());
+        assert!(tracker.recent_frames.is_empty());
+        assert_eq!(tracker.snapshot().bytes_received, 100);
+    }
+}
@@ -0,0 +1,88 @@
+use std::net::SocketAddr;
+
+use tokio::{
+    sync::broadcast::{error::RecvError, Receiver},
+    task::JoinHandle,
+};
+
+use crate::fm_network::action::{ActionKind, FMAction};
+
+/// Narrows which actions reach a listener, the default lets everything through.
+#[derive(Clone, Debug, Default)]
+pub struct ActionFilter {
+    kinds: Option<Vec<ActionKind>>,
+    addr: Option<SocketAddr>,
+}
+
+impl ActionFilter {
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    pub fn kinds<I: IntoIterator<Item = ActionKind>>(mut self, kinds: I) -> Self {
+        self.kinds = Some(kinds.into_iter().collect());
+        self
+    }
+
+    /// Only pass actions about `addr`, actions without a client (e.g. stream stats) still pass.
+    pub fn addr(mut self, addr: SocketAddr) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+
+    pub fn matches(&self, action: &FMAction) -> bool {
+        if let Some(kinds) = &self.kinds {
+            if !kinds.contains(&action.kind()) {
+                return false;
+            }
+        }
+
+        match (self.addr, action.addr()) {
+            (Some(expected), Some(addr)) => expected == addr,
+            _ => true,
+        }
+    }
+}
+
+/// Keeps a listener task running until it is dropped.
+#[must_use = "the listener is removed as soon as the subscription is dropped"]
+pub struct Subscription {
+    task: JoinHandle<()>,
+}
+
+impl Subscription {
+    /// Drains `receiver` on its own task so slow callbacks never hold up the sender.
+    pub(crate) fn spawn<F>(
+        mut receiver: Receiver<FMAction>,
+        filter: ActionFilter,
+        callback: F,
+    ) -> Self
+    where
+        F: Fn(&FMAction) + Send + Sync + 'static,
+    {
+        let task = tokio::task::spawn(async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(action) => {
+                        if filter.matches(&action) {
+                            callback(&action);
+                        }
+                    }
+                    Err(RecvError::Lagged(skipped)) => {
+                        eprintln!("Listener lagged behind, {} events skipped", skipped);
+                        callback(&FMAction::Lagged { skipped });
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        Self { task }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+
+use crate::fm_network::reliable::CommandOutcome;
+
+/// Which headsets a message is sent to.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Target {
+    /// Every client with a recent heartbeat.
+    All,
+    /// Clients with a recent heartbeat whose device record has this group.
+    Group(String),
+    /// `ip` or `ip:port`, the port telling apart headsets behind the same ip.
+    Addresses(Vec<String>),
+    /// A single datagram to `broadcast_address`, never acknowledged so never reliable.
+    Broadcast,
+}
+
+/// What happened to the message for one recipient of a [`Target`].
+#[derive(Serialize, Clone, Debug)]
+pub struct Delivery {
+    pub target: String,
+    pub device_name: Option<String>,
+    pub outcome: CommandOutcome,
+}
+
+impl Delivery {
+    pub fn new(target: String, device_name: Option<String>, outcome: CommandOutcome) -> Self {
+        Self {
+            target,
+            device_name,
+            outcome,
+        }
+    }
+}
@@ -0,0 +1,847 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::UNIX_EPOCH,
+};
+
+use rusqlite::{
+    params, params_from_iter, types::Value as SqlValue, Connection, OptionalExtension, Transaction,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::history_store::{self, SessionMeta, SessionRecord};
+use crate::play_history::{HistoryError, HistoryFileReport, PlayHistory};
+use crate::roster::Trainee;
+
+/// Bumped whenever [`SCHEMA`] changes; the index is then dropped and rebuilt from the files.
+const SCHEMA_VERSION: i32 = 2;
+
+const DROP_SCHEMA: &str = "
+DROP TABLE IF EXISTS stages;
+DROP TABLE IF EXISTS missions;
+DROP TABLE IF EXISTS sessions;
+";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS sessions (
+    id            INTEGER PRIMARY KEY,
+    session_id    TEXT NOT NULL UNIQUE,
+    user_id       TEXT NOT NULL,
+    received_at   INTEGER NOT NULL,
+    source        TEXT,
+    hash          TEXT NOT NULL,
+    path          TEXT NOT NULL UNIQUE,
+    file_modified INTEGER NOT NULL,
+    file_size     INTEGER NOT NULL,
+    history       TEXT NOT NULL,
+    UNIQUE (user_id, hash)
+);
+CREATE INDEX IF NOT EXISTS sessions_by_user ON sessions (user_id, received_at);
+CREATE INDEX IF NOT EXISTS sessions_by_time ON sessions (received_at);
+
+CREATE TABLE IF NOT EXISTS missions (
+    session  INTEGER NOT NULL REFERENCES sessions (id) ON DELETE CASCADE,
+    idx      INTEGER NOT NULL,
+    name     TEXT NOT NULL,
+    time     REAL NOT NULL,
+    complete INTEGER NOT NULL,
+    score    REAL NOT NULL,
+    PRIMARY KEY (session, idx)
+);
+CREATE INDEX IF NOT EXISTS missions_by_name ON missions (name);
+
+CREATE TABLE IF NOT EXISTS stages (
+    session  INTEGER NOT NULL,
+    mission  INTEGER NOT NULL,
+    idx      INTEGER NOT NULL,
+    stg_name TEXT NOT NULL,
+    score    REAL NOT NULL,
+    time     REAL NOT NULL,
+    PRIMARY KEY (session, mission, idx),
+    FOREIGN KEY (session, mission) REFERENCES missions (session, idx) ON DELETE CASCADE
+);
+";
+
+/// Page size used when a query doesn't ask for one.
+const DEFAULT_PAGE_SIZE: u32 = 50;
+const MAX_PAGE_SIZE: u32 = 500;
+
+/// SQLite index over the stored sessions, cheap to clone and held in Tauri managed state.
+///
+/// The session files stay the source of truth, the database can be rebuilt from them.
+#[derive(Clone)]
+pub struct HistoryDb {
+    conn: Arc<Mutex<Connection>>,
+}
+
+/// Filters for [`HistoryDb::query`], every field is optional.
+///
+/// Mission filters must all hold for the same mission of a session.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct HistoryQuery {
+    pub user_id: Option<String>,
+    /// Unix seconds, inclusive.
+    pub from: Option<u64>,
+    /// Unix seconds, exclusive.
+    pub to: Option<u64>,
+    pub mission: Option<String>,
+    pub complete: Option<bool>,
+    /// Bounds on a mission's score, the sum of its stage scores.
+    pub min_score: Option<f64>,
+    pub max_score: Option<f64>,
+    pub offset: u32,
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HistoryPage {
+    /// Sessions matching the filters across all pages.
+    pub total: u64,
+    pub sessions: Vec<SessionMeta>,
+    /// Roster entries of the users on this page, by user id.
+    pub trainees: HashMap<String, Trainee>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ImportReport {
+    /// Pre-session files copied into session storage.
+    pub migrated: usize,
+    pub added: usize,
+    /// Session files edited on disk since they were indexed.
+    pub updated: usize,
+    /// Session files deleted on disk.
+    pub removed: usize,
+    pub failed: Vec<HistoryFileReport>,
+}
+
+/// Modification time in milliseconds and size of an indexed file, to notice edits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileStamp {
+    modified: i64,
+    size: i64,
+}
+
+struct IndexedFile {
+    id: i64,
+    path: PathBuf,
+    stamp: FileStamp,
+}
+
+impl HistoryDb {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, HistoryError> {
+        if let Some(parent) = path.as_ref().parent() {
+            std::fs::create_dir_all(parent).ok();
+        }
+        Self::init(Connection::open(path)?)
+    }
+
+    /// A database that lives until the app exits, used when the file can't be opened.
+    pub fn in_memory() -> Result<Self, HistoryError> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, HistoryError> {
+        conn.pragma_update(None, "foreign_keys", true)?;
+        let version: i32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version != SCHEMA_VERSION {
+            conn.execute_batch(DROP_SCHEMA)?;
+            conn.pragma_update(None, "user_version", SCHEMA_VERSION)?;
+        }
+        conn.execute_batch(SCHEMA)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Indexes a stored session, `false` if the user already has one with the same content.
+    pub async fn insert(&self, record: &SessionRecord, path: &Path) -> Result<bool, HistoryError> {
+        let stamp = file_stamp(path)
+            .await
+            .ok_or_else(|| HistoryError::Io(format!("{} does not exist", path.display())))?;
+
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        let inserted = insert_session(&tx, record, path, stamp)?;
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// Copies pre-session `history_dir/*.json` files into session storage, then [`Self::sync`]s.
+    pub async fn import_dir(&self, history_dir: &Path) -> ImportReport {
+        let mut report = ImportReport::default();
+        migrate_legacy_files(history_dir, &mut report).await;
+        self.sync_into(history_dir, &mut report).await;
+        report
+    }
+
+    /// Brings the index in line with the session files: new files are added, edited ones
+    /// re-read and deleted ones dropped. Unchanged files are only `stat`ed.
+    pub async fn sync(&self, history_dir: &Path) -> ImportReport {
+        let mut report = ImportReport::default();
+        self.sync_into(history_dir, &mut report).await;
+        report
+    }
+
+    async fn sync_into(&self, history_dir: &Path, report: &mut ImportReport) {
+        let mut indexed = match self.indexed_files().await {
+            Ok(indexed) => indexed,
+            Err(e) => {
+                report.failed.push(HistoryFileReport {
+                    path: history_dir.display().to_string(),
+                    user_id: None,
+                    error: Some(e),
+                });
+                return;
+            }
+        };
+
+        for path in history_store::session_files(history_dir).await {
+            let known = indexed.remove(&path);
+            let stamp = file_stamp(&path).await;
+            if let (Some(known), Some(stamp)) = (&known, stamp) {
+                if known.stamp == stamp {
+                    continue;
+                }
+            }
+
+            let result = match history_store::load_session_file(&path).await {
+                Ok(record) => {
+                    self.replace(known.as_ref().map(|k| k.id), &record, &path)
+                        .await
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(_) if known.is_some() => report.updated += 1,
+                Ok(true) => report.added += 1,
+                Ok(false) => {}
+                Err(e) => report.failed.push(HistoryFileReport {
+                    path: path.display().to_string(),
+                    user_id: None,
+                    error: Some(e),
+                }),
+            }
+        }
+
+        // whatever is left was not found on disk
+        for file in indexed.into_values() {
+            match self.remove(file.id).await {
+                Ok(()) => report.removed += 1,
+                Err(e) => report.failed.push(HistoryFileReport {
+                    path: file.path.display().to_string(),
+                    user_id: None,
+                    error: Some(e),
+                }),
+            }
+        }
+    }
+
+    /// Finds a session by record id (`session_id`), file path or user id, in that order.
+    ///
+    /// A user id resolves to the user's newest session. The file is checked first, so
+    /// edits on disk are picked up and deleted files are dropped from the index.
+    pub async fn get(&self, key: &str) -> Result<Option<SessionRecord>, HistoryError> {
+        loop {
+            let Some(file) = self.lookup(key).await? else {
+                return Ok(None);
+            };
+
+            match file_stamp(&file.path).await {
+                // deleted, a user may still have older sessions
+                None => self.remove(file.id).await?,
+                Some(stamp) if stamp != file.stamp => {
+                    let record = history_store::load_session_file(&file.path).await?;
+                    self.replace(Some(file.id), &record, &file.path).await?;
+                    return Ok(Some(record));
+                }
+                Some(_) => return self.record(file.id).await,
+            }
+        }
+    }
+
+    async fn lookup(&self, key: &str) -> Result<Option<IndexedFile>, HistoryError> {
+        // session files are named after their id, so a path matches even with other separators
+        let file_stem = key
+            .rsplit(['/', '\\'])
+            .next()
+            .map(|name| name.trim_end_matches(".json"))
+            .unwrap_or(key);
+
+        let conn = self.conn.lock().await;
+        let mut statement = conn.prepare_cached(
+            "SELECT id, path, file_modified, file_size FROM sessions
+             WHERE session_id = ?1 OR path = ?1 OR session_id = ?2 OR user_id = ?1
+             ORDER BY session_id = ?1 DESC, path = ?1 DESC, session_id = ?2 DESC,
+                      received_at DESC, id DESC
+             LIMIT 1",
+        )?;
+        Ok(statement
+            .query_row([key, file_stem], read_indexed_file)
+            .optional()?)
+    }
+
+    async fn record(&self, id: i64) -> Result<Option<SessionRecord>, HistoryError> {
+        let conn = self.conn.lock().await;
+        let row = conn
+            .query_row(
+                "SELECT session_id, user_id, received_at, source, hash, history
+                 FROM sessions WHERE id = ?1",
+                [id],
+                |row| Ok((read_meta(row)?, row.get::<_, String>(5)?)),
+            )
+            .optional()?;
+
+        match row {
+            Some((meta, json)) => Ok(Some(SessionRecord {
+                meta,
+                history: PlayHistory::from_json(&json)?,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    async fn indexed_files(&self) -> Result<HashMap<PathBuf, IndexedFile>, HistoryError> {
+        let conn = self.conn.lock().await;
+        let mut statement =
+            conn.prepare("SELECT id, path, file_modified, file_size FROM sessions")?;
+        let files = statement
+            .query_map([], read_indexed_file)?
+            .map(|file| file.map(|file| (file.path.clone(), file)))
+            .collect::<Result<HashMap<_, _>, _>>()?;
+        Ok(files)
+    }
+
+    /// Re-indexes `record`, dropping the row `id` it replaces.
+    async fn replace(
+        &self,
+        id: Option<i64>,
+        record: &SessionRecord,
+        path: &Path,
+    ) -> Result<bool, HistoryError> {
+        let stamp = file_stamp(path)
+            .await
+            .ok_or_else(|| HistoryError::Io(format!("{} does not exist", path.display())))?;
+
+        let mut conn = self.conn.lock().await;
+        let tx = conn.transaction()?;
+        if let Some(id) = id {
+            tx.execute("DELETE FROM sessions WHERE id = ?1", [id])?;
+        }
+        let inserted = insert_session(&tx, record, path, stamp)?;
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    async fn remove(&self, id: i64) -> Result<(), HistoryError> {
+        let conn = self.conn.lock().await;
+        conn.execute("DELETE FROM sessions WHERE id = ?1", [id])?;
+        Ok(())
+    }
+
+    /// Sessions matching `query`, newest first.
+    pub async fn query(&self, query: &HistoryQuery) -> Result<HistoryPage, HistoryError> {
+        let (filter, mut args) = query.where_clause();
+        let conn = self.conn.lock().await;
+
+        let total: i64 = conn.query_row(
+            &format!("SELECT COUNT(*) FROM sessions s {}", filter),
+            params_from_iter(args.iter()),
+            |row| row.get(0),
+        )?;
+
+        let limit = query
+            .limit
+            .unwrap_or(DEFAULT_PAGE_SIZE)
+            .clamp(1, MAX_PAGE_SIZE);
+        args.push(SqlValue::Integer(limit as i64));
+        args.push(SqlValue::Integer(query.offset as i64));
+
+        let mut statement = conn.prepare(&format!(
+            "SELECT s.session_id, s.user_id, s.received_at, s.source, s.hash FROM sessions s {}
+             ORDER BY s.received_at DESC, s.id DESC LIMIT ? OFFSET ?",
+            filter
+        ))?;
+        let sessions = statement
+            .query_map(params_from_iter(args.iter()), read_meta)?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(HistoryPage {
+            total: total as u64,
+            sessions,
+            trainees: HashMap::new(),
+        })
+    }
+
+    /// Every session matching `query` with its history, by user and oldest first. Paging is ignored.
+    pub async fn records(&self, query: &HistoryQuery) -> Result<Vec<SessionRecord>, HistoryError> {
+        let (filter, args) = query.where_clause();
+        let conn = self.conn.lock().await;
+
+        let mut statement = conn.prepare(&format!(
+            "SELECT s.session_id, s.user_id, s.received_at, s.source, s.hash, s.history
+             FROM sessions s {} ORDER BY s.user_id, s.received_at, s.id",
+            filter
+        ))?;
+        let rows = statement
+            .query_map(params_from_iter(args.iter()), |row| {
+                Ok((read_meta(row)?, row.get::<_, String>(5)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        rows.into_iter()
+            .map(|(meta, json)| {
+                Ok(SessionRecord {
+                    meta,
+                    history: PlayHistory::from_json(&json)?,
+                })
+            })
+            .collect()
+    }
+
+    /// The newest session of every user.
+    pub async fn latest_per_user(&self) -> Result<Vec<SessionMeta>, HistoryError> {
+        let conn = self.conn.lock().await;
+        let mut statement = conn.prepare(
+            "SELECT session_id, user_id, received_at, source, hash FROM sessions s
+             WHERE s.id = (SELECT id FROM sessions WHERE user_id = s.user_id
+                           ORDER BY received_at DESC, id DESC LIMIT 1)",
+        )?;
+
+        let sessions = statement
+            .query_map([], read_meta)?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(sessions)
+    }
+}
+
+impl HistoryQuery {
+    fn where_clause(&self) -> (String, Vec<SqlValue>) {
+        let mut conditions = Vec::new();
+        let mut args = Vec::new();
+
+        if let Some(user_id) = &self.user_id {
+            conditions.push("s.user_id = ?");
+            args.push(SqlValue::Text(user_id.clone()));
+        }
+        if let Some(from) = self.from {
+            conditions.push("s.received_at >= ?");
+            args.push(SqlValue::Integer(from as i64));
+        }
+        if let Some(to) = self.to {
+            conditions.push("s.received_at < ?");
+            args.push(SqlValue::Integer(to as i64));
+        }
+
+        let mut mission_conditions = vec!["m.session = s.id"];
+        if let Some(mission) = &self.mission {
+            mission_conditions.push("m.name = ?");
+            args.push(SqlValue::Text(mission.clone()));
+        }
+        if let Some(complete) = self.complete {
+            mission_conditions.push("m.complete = ?");
+            args.push(SqlValue::Integer(complete as i64));
+        }
+        if let Some(min_score) = self.min_score {
+            mission_conditions.push("m.score >= ?");
+            args.push(SqlValue::Real(min_score));
+        }
+        if let Some(max_score) = self.max_score {
+            mission_conditions.push("m.score <= ?");
+            args.push(SqlValue::Real(max_score));
+        }
+
+        let mission_filter;
+        if mission_conditions.len() > 1 {
+            mission_filter = format!(
+                "EXISTS (SELECT 1 FROM missions m WHERE {})",
+                mission_conditions.join(" AND ")
+            );
+            conditions.push(&mission_filter);
+        }
+
+        if conditions.is_empty() {
+            (String::new(), args)
+        } else {
+            (format!("WHERE {}", conditions.join(" AND ")), args)
+        }
+    }
+}
+
+fn insert_session(
+    tx: &Transaction,
+    record: &SessionRecord,
+    path: &Path,
+    stamp: FileStamp,
+) -> Result<bool, HistoryError> {
+    let meta = &record.meta;
+    let history =
+        serde_json::to_string(&record.history).map_err(|e| HistoryError::Schema(e.to_string()))?;
+
+    let inserted = tx.execute(
+        "INSERT OR IGNORE INTO sessions (session_id, user_id, received_at, source, hash, path,
+                                         file_modified, file_size, history)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        params![
+            meta.session_id,
+            meta.user_id,
+            meta.received_at as i64,
+            meta.source.map(|addr| addr.to_string()),
+            meta.hash,
+            path.display().to_string(),
+            stamp.modified,
+            stamp.size,
+            history,
+        ],
+    )?;
+    if inserted == 0 {
+        return Ok(false);
+    }
+
+    let session = tx.last_insert_rowid();
+    for (i, mission) in record.history.mission_datas.iter().enumerate() {
+        let score: f64 = mission.stg_datas.iter().map(|stage| stage.score).sum();
+        tx.execute(
+            "INSERT INTO missions (session, idx, name, time, complete, score)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                session,
+                i as i64,
+                mission.name,
+                mission.time,
+                mission.complete,
+                score
+            ],
+        )?;
+
+        for (j, stage) in mission.stg_datas.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO stages (session, mission, idx, stg_name, score, time)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    session,
+                    i as i64,
+                    j as i64,
+                    stage.stg_name,
+                    stage.score,
+                    stage.time
+                ],
+            )?;
+        }
+    }
+    Ok(true)
+}
+
+fn read_meta(row: &rusqlite::Row) -> rusqlite::Result<SessionMeta> {
+    let source: Option<String> = row.get(3)?;
+    Ok(SessionMeta {
+        session_id: row.get(0)?,
+        user_id: row.get(1)?,
+        received_at: row.get::<_, i64>(2)? as u64,
+        source: source.and_then(|addr| addr.parse().ok()),
+        hash: row.get(4)?,
+    })
+}
+
+fn read_indexed_file(row: &rusqlite::Row) -> rusqlite::Result<IndexedFile> {
+    Ok(IndexedFile {
+        id: row.get(0)?,
+        path: PathBuf::from(row.get::<_, String>(1)?),
+        stamp: FileStamp {
+            modified: row.get(2)?,
+            size: row.get(3)?,
+        },
+    })
+}
+
+async fn file_stamp(path: &Path) -> Option<FileStamp> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|age| age.as_millis() as i64)
+        .unwrap_or_default();
+
+    Some(FileStamp {
+        modified,
+        size: metadata.len() as i64,
+    })
+}
+
+/// Stores every `history_dir/*.json` file written before sessions existed as a session,
+/// dated by its modification time, then moves the file to the imported directory.
+async fn migrate_legacy_files(history_dir: &Path, report: &mut ImportReport) {
+    let mut entries = match tokio::fs::read_dir(history_dir).await {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+
+        let received_at = entry
+            .metadata()
+            .await
+            .ok()
+            .and_then(|metadata| metadata.modified().ok())
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|age| age.as_secs())
+            .unwrap_or_else(history_store::unix_now);
+
+        let result = match PlayHistory::load(&path).await {
+            Ok(history) => {
+                history_store::save_session(history_dir, history, None, received_at).await
+            }
+            Err(e) => Err(e),
+        };
+        // a duplicate is stored already, the file is archived all the same
+        let result = match result {
+            Ok(saved) => history_store::archive_legacy_file(history_dir, &path)
+                .await
+                .map(|_| saved),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(saved) if !saved.duplicate => report.migrated += 1,
+            Ok(_) => {}
+            Err(e) => report.failed.push(HistoryFileReport {
+                path: path.display().to_string(),
+                user_id: None,
+                error: Some(e),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("history-db-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Saves and indexes a session with one single-stage mission per `(name, complete, score)`.
+    async fn add(
+        db: &HistoryDb,
+        dir: &Path,
+        user_id: &str,
+        received_at: u64,
+        missions: &[(&str, bool, f64)],
+    ) {
+        let missions: Vec<_> = missions
+            .iter()
+            .map(|(name, complete, score)| {
+                serde_json::json!({
+                    "name": name,
+                    "time": 60.0,
+                    "complete": complete,
+                    "stgDatas": [{"stgName": "s1", "score": score, "time": 60.0}],
+                })
+            })
+            .collect();
+        let json = serde_json::json!({"userId": user_id, "missionDatas": missions});
+        let history = PlayHistory::from_json(&json.to_string()).unwrap();
+
+        let saved = history_store::save_session(dir, history.clone(), None, received_at)
+            .await
+            .unwrap();
+        let record = SessionRecord {
+            meta: saved.meta,
+            history,
+        };
+        assert!(db.insert(&record, &saved.path).await.unwrap());
+    }
+
+    /// Four sessions of two users, received at 100, 150, 200 and 300.
+    async fn fixture(name: &str) -> (HistoryDb, PathBuf) {
+        let dir = test_dir(name);
+        let db = HistoryDb::in_memory().unwrap();
+        add(&db, &dir, "u1", 100, &[("Fire", true, 7.0)]).await;
+        add(&db, &dir, "u1", 200, &[("Flood", false, 2.0)]).await;
+        add(&db, &dir, "u2", 150, &[("Fire", false, 5.0)]).await;
+        add(
+            &db,
+            &dir,
+            "u2",
+            300,
+            &[("Fire", true, 9.0), ("Flood", true, 8.0)],
+        )
+        .await;
+        (db, dir)
+    }
+
+    async fn received(db: &HistoryDb, query: HistoryQuery) -> (u64, Vec<u64>) {
+        let page = db.query(&query).await.unwrap();
+        let times = page.sessions.iter().map(|meta| meta.received_at).collect();
+        (page.total, times)
+    }
+
+    #[test]
+    fn empty_query_has_no_where_clause() {
+        let (clause, args) = HistoryQuery::default().where_clause();
+        assert_eq!(clause, "");
+        assert!(args.is_empty());
+    }
+
+    #[test]
+    fn session_filters_come_before_mission_filters() {
+        let query = HistoryQuery {
+            user_id: Some("u1".into()),
+            from: Some(10),
+            to: Some(20),
+            mission: Some("Fire".into()),
+            complete: Some(true),
+            min_score: Some(1.5),
+            max_score: Some(9.0),
+            ..Default::default()
+        };
+        let (clause, args) = query.where_clause();
+
+        assert_eq!(
+            clause,
+            "WHERE s.user_id = ? AND s.received_at >= ? AND s.received_at < ? AND \
+             EXISTS (SELECT 1 FROM missions m WHERE m.session = s.id AND m.name = ? AND \
+             m.complete = ? AND m.score >= ? AND m.score <= ?)"
+        );
+        assert_eq!(
+            args,
+            vec![
+                SqlValue::Text("u1".into()),
+                SqlValue::Integer(10),
+                SqlValue::Integer(20),
+                SqlValue::Text("Fire".into()),
+                SqlValue::Integer(1),
+                SqlValue::Real(1.5),
+                SqlValue::Real(9.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn single_mission_filter_needs_a_matching_mission() {
+        let query = HistoryQuery {
+            complete: Some(false),
+            ..Default::default()
+        };
+        let (clause, args) = query.where_clause();
+
+        assert_eq!(
+            clause,
+            "WHERE EXISTS (SELECT 1 FROM missions m WHERE m.session = s.id AND m.complete = ?)"
+        );
+        assert_eq!(args, vec![SqlValue::Integer(0)]);
+    }
+
+    #[tokio::test]
+    async fn legacy_files_are_migrated_once() {
+        let dir = std::env::temp_dir().join(format!("history-db-migrate-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("u1.json"), r#"{"userId":"u1","missionDatas":[]}"#).unwrap();
+
+        let db = HistoryDb::in_memory().unwrap();
+        let report = db.import_dir(&dir).await;
+        assert_eq!((report.migrated, report.added), (1, 1));
+        assert!(!dir.join("u1.json").exists());
+        assert!(dir.join("imported").join("u1.json").exists());
+
+        // a deleted session stays deleted
+        for path in history_store::session_files(&dir).await {
+            std::fs::remove_file(path).unwrap();
+        }
+        let report = db.import_dir(&dir).await;
+        assert_eq!((report.migrated, report.added, report.removed), (0, 0, 1));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn query_filters_by_user_and_date() {
+        let (db, dir) = fixture("query-user").await;
+
+        let by_user = HistoryQuery {
+            user_id: Some("u1".into()),
+            ..Default::default()
+        };
+        assert_eq!(received(&db, by_user).await, (2, vec![200, 100]));
+
+        let by_date = HistoryQuery {
+            from: Some(150),
+            to: Some(300),
+            ..Default::default()
+        };
+        assert_eq!(received(&db, by_date).await, (2, vec![200, 150]));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn query_mission_filters_hold_for_one_mission() {
+        let (db, dir) = fixture("query-mission").await;
+
+        let completed_fire = HistoryQuery {
+            mission: Some("Fire".into()),
+            complete: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(received(&db, completed_fire).await, (2, vec![300, 100]));
+
+        let failed_flood = HistoryQuery {
+            mission: Some("Flood".into()),
+            complete: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(received(&db, failed_flood).await, (1, vec![200]));
+        // u2's last session scored above 8.5 on Fire, not on Flood
+        let high_flood = HistoryQuery {
+            mission: Some("Flood".into()),
+            min_score: Some(8.5),
+            ..Default::default()
+        };
+        assert_eq!(received(&db, high_flood).await, (0, vec![]));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn query_pages_keep_the_total() {
+        let (db, dir) = fixture("query-pages").await;
+        let page = |offset| HistoryQuery {
+            offset,
+            limit: Some(3),
+            ..Default::default()
+        };
+
+        assert_eq!(received(&db, page(0)).await, (4, vec![300, 200, 150]));
+        assert_eq!(received(&db, page(3)).await, (4, vec![100]));
+        assert_eq!(received(&db, page(4)).await, (4, vec![]));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn records_ignore_paging_and_group_by_user() {
+        let (db, dir) = fixture("records").await;
+        let query = HistoryQuery {
+            mission: Some("Fire".into()),
+            limit: Some(1),
+            ..Default::default()
+        };
+
+        let records = db.records(&query).await.unwrap();
+        let order: Vec<(&str, u64)> = records
+            .iter()
+            .map(|record| (record.meta.user_id.as_str(), record.meta.received_at))
+            .collect();
+        assert_eq!(order, vec![("u1", 100), ("u2", 150), ("u2", 300)]);
+        assert_eq!(records[2].history.mission_datas.len(), 2);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,334 @@
+use std::path::{Path, PathBuf};
+
+use rust_xlsxwriter::{ExcelDateTime, Format, Workbook, XlsxError};
+use serde::{Deserialize, Serialize};
+
+use crate::history_store::{self, SessionRecord};
+use crate::play_history::HistoryError;
+use crate::roster::Roster;
+
+const HEADERS: [&str; 11] = [
+    "User",
+    "Name",
+    "Group",
+    "Session",
+    "Received (UTC)",
+    "Mission",
+    "Mission time",
+    "Complete",
+    "Stage",
+    "Score",
+    "Stage time",
+];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    Csv,
+    Xlsx,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ExportSummary {
+    pub path: PathBuf,
+    pub format: ExportFormat,
+    pub sessions: usize,
+    pub rows: usize,
+}
+
+/// One stage of one mission, a mission without stages still gets a row.
+struct ExportRow<'a> {
+    user_id: &'a str,
+    /// Name and group from the roster, empty for unknown users.
+    name: &'a str,
+    group: &'a str,
+    session_id: &'a str,
+    received_at: u64,
+    mission: &'a str,
+    mission_time: f64,
+    complete: bool,
+    stage: Option<(&'a str, f64, f64)>,
+}
+
+impl From<XlsxError> for HistoryError {
+    fn from(e: XlsxError) -> Self {
+        Self::Export(e.to_string())
+    }
+}
+
+impl From<csv::Error> for HistoryError {
+    fn from(e: csv::Error) -> Self {
+        Self::Export(e.to_string())
+    }
+}
+
+impl ExportFormat {
+    /// The explicit format or the one matching the extension of `path`.
+    pub fn resolve(format: Option<Self>, path: &Path) -> Result<Self, HistoryError> {
+        if let Some(format) = format {
+            return Ok(format);
+        }
+
+        match path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase())
+            .as_deref()
+        {
+            Some("csv") => Ok(Self::Csv),
+            Some("xlsx") => Ok(Self::Xlsx),
+            _ => Err(HistoryError::Export(format!(
+                "can't tell the format of {}, use a .csv or .xlsx file",
+                path.display()
+            ))),
+        }
+    }
+}
+
+/// Writes one row per stage of every session to `path`, replacing the file if it exists.
+pub async fn export(
+    records: &[SessionRecord],
+    roster: &Roster,
+    path: &Path,
+    format: ExportFormat,
+) -> Result<ExportSummary, HistoryError> {
+    let rows = flatten(records, roster);
+    let data = match format {
+        ExportFormat::Csv => to_csv(&rows)?,
+        ExportFormat::Xlsx => to_xlsx(&rows)?,
+    };
+    history_store::write_atomic(path, &data).await?;
+
+    Ok(ExportSummary {
+        path: path.to_path_buf(),
+        format,
+        sessions: records.len(),
+        rows: rows.len(),
+    })
+}
+
+fn flatten<'a>(records: &'a [SessionRecord], roster: &'a Roster) -> Vec<ExportRow<'a>> {
+    let mut rows = Vec::new();
+    for record in records {
+        let trainee = roster.get(&record.meta.user_id);
+        let name = trainee.map(|trainee| trainee.name.as_str());
+        let group = trainee.and_then(|trainee| trainee.group.as_deref());
+
+        for mission in &record.history.mission_datas {
+            let row = |stage| ExportRow {
+                user_id: &record.meta.user_id,
+                name: name.unwrap_or_default(),
+                group: group.unwrap_or_default(),
+                session_id: &record.meta.session_id,
+                received_at: record.meta.received_at,
+                mission: &mission.name,
+                mission_time: mission.time,
+                complete: mission.complete,
+                stage,
+            };
+
+            if mission.stg_datas.is_empty() {
+                rows.push(row(None));
+            }
+            for stage in &mission.stg_datas {
+                rows.push(row(Some((&stage.stg_name, stage.score, stage.time))));
+            }
+        }
+    }
+    rows
+}
+
+fn to_csv(rows: &[ExportRow]) -> Result<Vec<u8>, HistoryError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(HEADERS)?;
+
+    for row in rows {
+        let (stage, score, time) = match row.stage {
+            Some((name, score, time)) => (name.to_owned(), score.to_string(), time.to_string()),
+            None => Default::default(),
+        };
+        writer.write_record([
+            row.user_id,
+            row.name,
+            row.group,
+            row.session_id,
+            &history_store::utc_timestamp(row.received_at),
+            row.mission,
+            &row.mission_time.to_string(),
+            &row.complete.to_string(),
+            &stage,
+            &score,
+            &time,
+        ])?;
+    }
+
+    writer
+        .into_inner()
+        .map_err(|e| HistoryError::Export(e.to_string()))
+}
+
+fn to_xlsx(rows: &[ExportRow]) -> Result<Vec<u8>, HistoryError> {
+    let mut workbook = Workbook::new();
+    let sheet = workbook.add_worksheet();
+    sheet.set_name("Play histories")?;
+
+    let bold = Format::new().set_bold();
+    let date = Format::new().set_num_format("yyyy-mm-dd hh:mm:ss");
+    sheet.write_row_with_format(0, 0, HEADERS, &bold)?;
+    sheet.set_freeze_panes(1, 0)?;
+
+    for (i, row) in rows.iter().enumerate() {
+        let at = i as u32 + 1;
+        sheet.write_string(at, 0, row.user_id)?;
+        sheet.write_string(at, 1, row.name)?;
+        sheet.write_string(at, 2, row.group)?;
+        sheet.write_string(at, 3, row.session_id)?;
+        sheet.write_datetime_with_format(
+            at,
+            4,
+            &ExcelDateTime::from_timestamp(row.received_at as i64)?,
+            &date,
+        )?;
+        sheet.write_string(at, 5, row.mission)?;
+        sheet.write_number(at, 6, row.mission_time)?;
+        sheet.write_boolean(at, 7, row.complete)?;
+        if let Some((name, score, time)) = row.stage {
+            sheet.write_string(at, 8, name)?;
+            sheet.write_number(at, 9, score)?;
+            sheet.write_number(at, 10, time)?;
+        }
+    }
+    sheet.autofit();
+
+    Ok(workbook.save_to_buffer()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history_store::SessionMeta;
+    use crate::play_history::PlayHistory;
+    use crate::roster::Trainee;
+
+    fn record(user_id: &str, session_id: &str, missions: serde_json::Value) -> SessionRecord {
+        let json = serde_json::json!({"userId": user_id, "missionDatas": missions});
+        SessionRecord {
+            meta: SessionMeta {
+                session_id: session_id.into(),
+                user_id: user_id.into(),
+                received_at: 86_400,
+                source: None,
+                hash: String::new(),
+            },
+            history: PlayHistory::from_json(&json.to_string()).unwrap(),
+        }
+    }
+
+    fn records() -> Vec<SessionRecord> {
+        vec![
+            record(
+                "u1",
+                "s1",
+                serde_json::json!([
+                    {"name": "Fire", "time": 120.0, "complete": true, "stgDatas": [
+                        {"stgName": "a", "score": 3.0, "time": 40.0},
+                        {"stgName": "b", "score": 4.5, "time": 80.0},
+                    ]},
+                    {"name": "Drill", "time": 10.0, "complete": false, "stgDatas": []},
+                ]),
+            ),
+            record(
+                "u2",
+                "s2",
+                serde_json::json!([
+                    {"name": "Fire", "time": 90.0, "complete": false, "stgDatas": [
+                        {"stgName": "a", "score": 1.0, "time": 90.0},
+                    ]},
+                ]),
+            ),
+        ]
+    }
+
+    /// A roster knowing only u1, whose name needs quoting in CSV.
+    fn roster() -> Roster {
+        let path = std::env::temp_dir().join(format!("export-roster-{}.json", std::process::id()));
+        let mut roster = Roster::load(path);
+        roster
+            .upsert(Trainee {
+                user_id: "u1".into(),
+                name: "Lee, \"Ann\"".into(),
+                group: Some("A".into()),
+                external_id: None,
+            })
+            .unwrap();
+        roster
+    }
+
+    #[test]
+    fn one_row_per_stage_with_its_mission_and_session() {
+        let records = records();
+        let roster = roster();
+        let rows = flatten(&records, &roster);
+
+        let summary: Vec<_> = rows
+            .iter()
+            .map(|row| (row.user_id, row.session_id, row.mission, row.stage))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("u1", "s1", "Fire", Some(("a", 3.0, 40.0))),
+                ("u1", "s1", "Fire", Some(("b", 4.5, 80.0))),
+                ("u1", "s1", "Drill", None),
+                ("u2", "s2", "Fire", Some(("a", 1.0, 90.0))),
+            ]
+        );
+        assert_eq!((rows[0].name, rows[0].group), ("Lee, \"Ann\"", "A"));
+        assert_eq!((rows[3].name, rows[3].group), ("", ""));
+        assert_eq!((rows[0].mission_time, rows[0].complete), (120.0, true));
+    }
+
+    #[test]
+    fn csv_has_the_headers_and_quotes_names() {
+        let records = records();
+        let csv = to_csv(&flatten(&records, &roster())).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "User,Name,Group,Session,Received (UTC),Mission,Mission time,Complete,Stage,Score,Stage time"
+        );
+        assert_eq!(
+            lines[1],
+            r#"u1,"Lee, ""Ann""",A,s1,1970-01-02 00:00:00,Fire,120,true,a,3,40"#
+        );
+        assert_eq!(
+            lines[3],
+            r#"u1,"Lee, ""Ann""",A,s1,1970-01-02 00:00:00,Drill,10,false,,,"#
+        );
+        assert_eq!(lines.len(), 5);
+
+        let mut reader = csv::Reader::from_reader(csv.as_bytes());
+        for row in reader.records() {
+            assert_eq!(row.unwrap().len(), HEADERS.len());
+        }
+    }
+
+    #[tokio::test]
+    async fn export_writes_the_file_and_counts_rows() {
+        let dir = std::env::temp_dir().join(format!("history-export-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sessions.xlsx");
+
+        let format = ExportFormat::resolve(None, &path).unwrap();
+        let summary = export(&records(), &roster(), &path, format).await.unwrap();
+
+        assert_eq!(summary.format, ExportFormat::Xlsx);
+        assert_eq!((summary.sessions, summary.rows), (2, 4));
+        assert!(std::fs::read(&path).unwrap().starts_with(b"PK"));
+        assert!(ExportFormat::resolve(None, &dir.join("sessions.txt")).is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
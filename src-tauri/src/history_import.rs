@@ -0,0 +1,361 @@
+use std::{
+    io::{Cursor, Read},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::Serialize;
+use zip::{DateTime, ZipArchive};
+
+use crate::history_store;
+use crate::play_history::{HistoryError, PlayHistory};
+
+/// Largest history file accepted, the same bound as a history received over the network.
+const MAX_FILE_LEN: u64 = 4 * 1024 * 1024;
+
+/// One history file found by [`collect`], parsed.
+pub struct ImportCandidate {
+    /// The file, or `archive.zip/entry.json` inside a zip.
+    pub file: String,
+    /// Unix seconds the file or zip entry was last modified, used as the session's receive time.
+    pub received_at: u64,
+    pub history: Result<PlayHistory, HistoryError>,
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Imported,
+    /// The user already has a session with the same content.
+    Duplicate,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct ImportFileReport {
+    pub file: String,
+    pub status: ImportStatus,
+    pub user_id: Option<String>,
+    pub session_id: Option<String>,
+    pub error: Option<HistoryError>,
+    /// What [`PlayHistory::validate`] found wrong with a history that was stored anyway.
+    pub problems: Option<HistoryError>,
+}
+
+/// Reads every history in `path`: a JSON file, a zip of JSON files or a folder of
+/// either, searched recursively. Histories are not validated, like received ones they are
+/// stored with their problems.
+pub async fn collect(path: &Path) -> Result<Vec<ImportCandidate>, HistoryError> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .map_err(|e| HistoryError::Io(format!("{}: {}", path.display(), e)))?;
+
+    let files = if metadata.is_dir() {
+        walk(path).await
+    } else {
+        vec![path.to_path_buf()]
+    };
+
+    let mut candidates = Vec::new();
+    for file in files {
+        let received_at = modified_at(&file).await;
+        let data = match read_limited(&file).await {
+            Ok(data) => data,
+            Err(e) => {
+                candidates.push(ImportCandidate {
+                    file: file.display().to_string(),
+                    received_at,
+                    history: Err(e),
+                });
+                continue;
+            }
+        };
+
+        if has_extension(&file, "zip") {
+            candidates.extend(read_zip(&file, data, received_at));
+        } else {
+            candidates.push(ImportCandidate {
+                file: file.display().to_string(),
+                received_at,
+                history: parse(&data),
+            });
+        }
+    }
+    Ok(candidates)
+}
+
+/// Every `.json` and `.zip` file below `dir`, sorted.
+async fn walk(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+
+    while let Some(dir) = dirs.pop() {
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", dir.display(), e);
+                continue;
+            }
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            match entry.file_type().await {
+                Ok(kind) if kind.is_dir() => dirs.push(path),
+                Ok(_) if has_extension(&path, "json") || has_extension(&path, "zip") => {
+                    files.push(path)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    files.sort();
+    files
+}
+
+fn read_zip(file: &Path, data: Vec<u8>, received_at: u64) -> Vec<ImportCandidate> {
+    let failed = |e: String| {
+        vec![ImportCandidate {
+            file: file.display().to_string(),
+            received_at,
+            history: Err(HistoryError::Io(e)),
+        }]
+    };
+    let mut archive = match ZipArchive::new(Cursor::new(data)) {
+        Ok(archive) => archive,
+        Err(e) => return failed(format!("not a zip archive, {}", e)),
+    };
+
+    let mut candidates = Vec::new();
+    for i in 0..archive.len() {
+        let mut entry = match archive.by_index(i) {
+            Ok(entry) => entry,
+            Err(e) => return failed(format!("broken zip archive, {}", e)),
+        };
+        let name = format!("{}/{}", file.display(), entry.name());
+        if entry.is_dir() || !has_extension(Path::new(entry.name()), "json") {
+            continue;
+        }
+
+        // the declared size can't be trusted, never inflate more than the limit
+        let mut data = Vec::new();
+        let history = match (&mut entry).take(MAX_FILE_LEN + 1).read_to_end(&mut data) {
+            Ok(_) if data.len() as u64 > MAX_FILE_LEN => Err(too_large()),
+            Ok(_) => parse(&data),
+            Err(e) => Err(HistoryError::Io(format!("{}: {}", name, e))),
+        };
+        candidates.push(ImportCandidate {
+            file: name,
+            received_at: entry
+                .last_modified()
+                .and_then(unix_secs)
+                .unwrap_or(received_at),
+            history,
+        });
+    }
+    candidates
+}
+
+fn parse(data: &[u8]) -> Result<PlayHistory, HistoryError> {
+    let json = std::str::from_utf8(data).map_err(|e| HistoryError::Schema(e.to_string()))?;
+    // a UTF-8 BOM is common in files saved by hand on Windows
+    PlayHistory::from_json(json.trim_start_matches('\u{feff}'))
+}
+
+async fn read_limited(path: &Path) -> Result<Vec<u8>, HistoryError> {
+    let io_error = |e: std::io::Error| HistoryError::Io(format!("{}: {}", path.display(), e));
+    let len = tokio::fs::metadata(path).await.map_err(io_error)?.len();
+    // a zip holds many histories, its entries are checked one by one instead
+    if len > MAX_FILE_LEN && !has_extension(path, "zip") {
+        return Err(too_large());
+    }
+    tokio::fs::read(path).await.map_err(io_error)
+}
+
+async fn modified_at(path: &Path) -> u64 {
+    tokio::fs::metadata(path)
+        .await
+        .ok()
+        .and_then(|metadata| metadata.modified().ok())
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|age| age.as_secs())
+        .unwrap_or_else(history_store::unix_now)
+}
+
+/// Unix seconds of a zip entry's modification time. Zip times carry no zone, they are
+/// read as UTC.
+fn unix_secs(time: DateTime) -> Option<u64> {
+    if !time.is_valid() {
+        return None;
+    }
+
+    // civil date to days, http://howardhinnant.github.io/date_algorithms.html
+    let month = time.month() as i64;
+    let year = time.year() as i64 - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + time.day() as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+
+    let secs = time.hour() as i64 * 3600 + time.minute() as i64 * 60 + time.second() as i64;
+    u64::try_from(days * 86_400 + secs).ok()
+}
+
+fn too_large() -> HistoryError {
+    HistoryError::Io(format!("larger than {} bytes", MAX_FILE_LEN))
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case(extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::SimpleFileOptions, ZipWriter};
+
+    use super::*;
+
+    /// An empty directory of its own for one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("history-import-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn history(user_id: &str) -> String {
+        format!(r#"{{"userId":"{}","missionDatas":[]}}"#, user_id)
+    }
+
+    /// Writes a zip of `entries`, each modified at `modified`.
+    fn write_zip(path: &Path, entries: &[(&str, &[u8])], modified: DateTime) {
+        let mut zip = ZipWriter::new(std::fs::File::create(path).unwrap());
+        let options = SimpleFileOptions::default().last_modified_time(modified);
+        for (name, data) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    fn user_ids(candidates: &[ImportCandidate]) -> Vec<Option<&str>> {
+        candidates
+            .iter()
+            .map(|candidate| candidate.history.as_ref().ok().map(|h| h.user_id.as_str()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn reads_a_single_file() {
+        let dir = test_dir("file");
+        let path = dir.join("u1.json");
+        std::fs::write(&path, history("u1")).unwrap();
+
+        let candidates = collect(&path).await.unwrap();
+        assert_eq!(user_ids(&candidates), [Some("u1")]);
+        assert_eq!(candidates[0].file, path.display().to_string());
+        assert_eq!(candidates[0].received_at, modified_at(&path).await);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn skips_a_utf8_bom() {
+        let dir = test_dir("bom");
+        let path = dir.join("u1.json");
+        std::fs::write(&path, format!("\u{feff}{}", history("u1"))).unwrap();
+
+        assert_eq!(user_ids(&collect(&path).await.unwrap()), [Some("u1")]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn keeps_histories_that_fail_validation() {
+        let dir = test_dir("invalid");
+        let path = dir.join("blank.json");
+        std::fs::write(&path, history(" ")).unwrap();
+
+        let candidates = collect(&path).await.unwrap();
+        let history = candidates[0].history.as_ref().unwrap();
+        assert!(history.validate().is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn walks_a_folder_for_json_and_zip_files() {
+        let dir = test_dir("folder");
+        std::fs::create_dir_all(dir.join("b")).unwrap();
+        std::fs::write(dir.join("a.json"), history("u1")).unwrap();
+        std::fs::write(dir.join("b").join("c.json"), history("u2")).unwrap();
+        std::fs::write(dir.join("notes.txt"), "not a history").unwrap();
+        let u3 = history("u3");
+        write_zip(
+            &dir.join("b").join("d.zip"),
+            &[("u3.json", u3.as_bytes())],
+            DateTime::default(),
+        );
+
+        let candidates = collect(&dir).await.unwrap();
+        assert_eq!(user_ids(&candidates), [Some("u1"), Some("u2"), Some("u3")]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn zip_entries_keep_their_own_time() {
+        let dir = test_dir("zip");
+        let path = dir.join("export.zip");
+        let (u1, u2) = (history("u1"), history("u2"));
+        let modified = DateTime::from_date_and_time(2024, 5, 6, 7, 8, 10).unwrap();
+        write_zip(
+            &path,
+            &[
+                ("u1.json", u1.as_bytes()),
+                ("readme.txt", b"skipped"),
+                ("old/u2.json", u2.as_bytes()),
+            ],
+            modified,
+        );
+
+        let candidates = collect(&path).await.unwrap();
+        assert_eq!(user_ids(&candidates), [Some("u1"), Some("u2")]);
+        assert_eq!(
+            candidates[1].file,
+            format!("{}/old/u2.json", path.display())
+        );
+        assert!(candidates
+            .iter()
+            .all(|candidate| candidate.received_at == 1_714_979_290));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn rejects_files_over_the_limit() {
+        let dir = test_dir("limit");
+        let mut large = history("u1").into_bytes();
+        large.resize(MAX_FILE_LEN as usize + 1, b' ');
+        std::fs::write(dir.join("large.json"), &large).unwrap();
+        write_zip(
+            &dir.join("large.zip"),
+            &[("large.json", &large)],
+            DateTime::default(),
+        );
+
+        let candidates = collect(&dir).await.unwrap();
+        assert_eq!(candidates.len(), 2);
+        for candidate in candidates {
+            assert!(matches!(candidate.history, Err(HistoryError::Io(_))));
+        }
+
+        // exactly at the limit is fine
+        large.truncate(MAX_FILE_LEN as usize);
+        std::fs::write(dir.join("large.json"), &large).unwrap();
+        let candidates = collect(&dir.join("large.json")).await.unwrap();
+        assert_eq!(user_ids(&candidates), [Some("u1")]);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
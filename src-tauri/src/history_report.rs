@@ -0,0 +1,537 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use printpdf::{
+    image_crate, BuiltinFont, Image, ImageTransform, IndirectFontRef, Line, Mm, PdfDocument,
+    PdfDocumentReference, PdfLayerReference, Point,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::history_store::{self, SessionRecord};
+use crate::play_history::{HistoryError, MissionData};
+use crate::roster::{Roster, Trainee};
+
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 20.0;
+const LOGO_HEIGHT: f32 = 20.0;
+const LINE_HEIGHT: f32 = 6.0;
+
+/// Left edge of the stage, score and time columns.
+const COLUMNS: [f32; 3] = [MARGIN, 120.0, 155.0];
+
+/// Editable layout and pass rules of the trainee report, stored as JSON.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReportTemplate {
+    pub title: String,
+    /// Printed under the title, e.g. the organisation and drill name.
+    pub header_lines: Vec<String>,
+    /// PNG or JPEG printed in the top right corner.
+    pub logo_path: Option<PathBuf>,
+    /// TrueType font for text the built-in Helvetica can't show, e.g. Chinese. Defaults to
+    /// `report_font_path` of the app config.
+    pub font_path: Option<PathBuf>,
+    /// Lowest total stage score of a passed mission.
+    pub pass_score: f64,
+    /// `pass_score` per mission name.
+    pub mission_pass_scores: HashMap<String, f64>,
+    /// A mission that wasn't completed fails regardless of its score.
+    pub require_complete: bool,
+    pub footer: Option<String>,
+    pub labels: ReportLabels,
+}
+
+/// Every fixed text of the report, so it can be translated.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ReportLabels {
+    pub trainee: String,
+    pub name: String,
+    pub group: String,
+    pub received_at: String,
+    pub result: String,
+    pub stage: String,
+    pub score: String,
+    pub time: String,
+    pub total: String,
+    pub complete: String,
+    pub incomplete: String,
+    pub pass: String,
+    pub fail: String,
+}
+
+/// Outcome of one report of a batch.
+#[derive(Serialize, Clone, Debug)]
+pub struct ReportOutcome {
+    pub user_id: String,
+    pub session_id: String,
+    pub path: PathBuf,
+    pub error: Option<HistoryError>,
+}
+
+impl Default for ReportTemplate {
+    fn default() -> Self {
+        Self {
+            title: "Training report".into(),
+            header_lines: Vec::new(),
+            logo_path: None,
+            font_path: None,
+            pass_score: 0.0,
+            mission_pass_scores: HashMap::new(),
+            require_complete: true,
+            footer: None,
+            labels: ReportLabels::default(),
+        }
+    }
+}
+
+impl Default for ReportLabels {
+    fn default() -> Self {
+        Self {
+            trainee: "Trainee".into(),
+            name: "Name".into(),
+            group: "Group".into(),
+            received_at: "Recorded (UTC)".into(),
+            result: "Result".into(),
+            stage: "Stage".into(),
+            score: "Score".into(),
+            time: "Time".into(),
+            total: "Total".into(),
+            complete: "complete".into(),
+            incomplete: "incomplete".into(),
+            pass: "PASS".into(),
+            fail: "FAIL".into(),
+        }
+    }
+}
+
+impl ReportTemplate {
+    /// Reads the template file, using the default template if it is missing or invalid.
+    pub async fn load(path: &Path) -> Self {
+        let template = match tokio::fs::read_to_string(path).await {
+            Ok(json) => match serde_json::from_str::<Self>(&json) {
+                Ok(template) => template,
+                Err(e) => {
+                    eprintln!("Error parsing report template {}: {}", path.display(), e);
+                    return Self::default();
+                }
+            },
+            Err(_) => return Self::default(),
+        };
+
+        match template.validate() {
+            Ok(()) => template,
+            Err(e) => {
+                eprintln!("Invalid report template {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Validates and persists the template.
+    pub async fn save(&self, path: &Path) -> Result<(), String> {
+        self.validate()?;
+        let json = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        history_store::write_atomic(path, json.as_bytes())
+            .await
+            .map_err(|e| format!("Error writing report template: {}", e))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let scores = std::iter::once(&self.pass_score).chain(self.mission_pass_scores.values());
+        for score in scores {
+            if !score.is_finite() {
+                return Err("pass scores must be finite numbers".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Total stage score and time of `mission` and whether it passed.
+    fn grade(&self, mission: &MissionData) -> (f64, f64, bool) {
+        let score: f64 = mission.stg_datas.iter().map(|stage| stage.score).sum();
+        let time: f64 = mission.stg_datas.iter().map(|stage| stage.time).sum();
+        let threshold = self
+            .mission_pass_scores
+            .get(&mission.name)
+            .copied()
+            .unwrap_or(self.pass_score);
+
+        let passed = (mission.complete || !self.require_complete) && score >= threshold;
+        (score, time, passed)
+    }
+
+    /// A session passes when it has missions and every one of them passed.
+    pub fn passed(&self, record: &SessionRecord) -> bool {
+        let missions = &record.history.mission_datas;
+        !missions.is_empty() && missions.iter().all(|mission| self.grade(mission).2)
+    }
+}
+
+/// Renders `record` into a PDF at `path`, replacing the file if it exists.
+pub async fn generate(
+    template: &ReportTemplate,
+    record: &SessionRecord,
+    trainee: Option<&Trainee>,
+    path: &Path,
+) -> Result<(), HistoryError> {
+    let assets = Assets::load(template).await?;
+    // the document isn't `Send`, it must not live across an await
+    let data = render(template, &assets, record, trainee)?;
+    history_store::write_atomic(path, &data).await
+}
+
+/// Renders one report per record into `dir`, named after the user and session.
+pub async fn generate_batch(
+    template: &ReportTemplate,
+    records: &[SessionRecord],
+    roster: &Roster,
+    dir: &Path,
+) -> Result<Vec<ReportOutcome>, HistoryError> {
+    let assets = Assets::load(template).await?;
+
+    let mut outcomes = Vec::new();
+    for record in records {
+        let path = dir.join(format!(
+            "{}-{}.pdf",
+            history_store::file_name(&record.meta.user_id),
+            record.meta.session_id
+        ));
+        let trainee = roster.get(&record.meta.user_id);
+        let result = match render(template, &assets, record, trainee) {
+            Ok(data) => history_store::write_atomic(&path, &data).await,
+            Err(e) => Err(e),
+        };
+        outcomes.push(ReportOutcome {
+            user_id: record.meta.user_id.clone(),
+            session_id: record.meta.session_id.clone(),
+            path,
+            error: result.err(),
+        });
+    }
+    Ok(outcomes)
+}
+
+/// Logo and font files of a template, read once per batch.
+struct Assets {
+    logo: Option<image_crate::DynamicImage>,
+    font: Option<Vec<u8>>,
+}
+
+impl Assets {
+    async fn load(template: &ReportTemplate) -> Result<Self, HistoryError> {
+        let read = |path: PathBuf| async move {
+            tokio::fs::read(&path)
+                .await
+                .map_err(|e| HistoryError::Io(format!("{}: {}", path.display(), e)))
+        };
+
+        let logo = match &template.logo_path {
+            Some(path) => Some(
+                image_crate::load_from_memory(&read(path.clone()).await?)
+                    .map_err(|e| HistoryError::Export(format!("logo {}: {}", path.display(), e)))?,
+            ),
+            None => None,
+        };
+        let font = match &template.font_path {
+            Some(path) => Some(read(path.clone()).await?),
+            None => None,
+        };
+        Ok(Self { logo, font })
+    }
+}
+
+/// Writes lines top to bottom, starting a new page when one is full.
+struct PageWriter {
+    doc: PdfDocumentReference,
+    layer: PdfLayerReference,
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+    /// Distance of the next line from the bottom edge in mm.
+    y: f32,
+}
+
+impl PageWriter {
+    fn new(title: &str, font: Option<&[u8]>) -> Result<Self, HistoryError> {
+        let (doc, page, layer) = PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Report");
+        let (regular, bold) = match font {
+            Some(font) => {
+                let font = doc.add_external_font(font).map_err(export_error)?;
+                (font.clone(), font)
+            }
+            None => (
+                doc.add_builtin_font(BuiltinFont::Helvetica)
+                    .map_err(export_error)?,
+                doc.add_builtin_font(BuiltinFont::HelveticaBold)
+                    .map_err(export_error)?,
+            ),
+        };
+
+        let layer = doc.get_page(page).get_layer(layer);
+        Ok(Self {
+            doc,
+            layer,
+            regular,
+            bold,
+            y: PAGE_HEIGHT - MARGIN,
+        })
+    }
+
+    /// Moves down by `height`, continuing on a new page if it doesn't fit.
+    fn advance(&mut self, height: f32) {
+        if self.y - height < MARGIN {
+            let (page, layer) = self.doc.add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "Report");
+            self.layer = self.doc.get_page(page).get_layer(layer);
+            self.y = PAGE_HEIGHT - MARGIN;
+        }
+        self.y -= height;
+    }
+
+    fn text(&mut self, text: &str, size: f32, bold: bool) {
+        self.advance(size * 0.5);
+        self.write_at(text, size, MARGIN, bold);
+    }
+
+    fn write_at(&self, text: &str, size: f32, x: f32, bold: bool) {
+        let font = if bold { &self.bold } else { &self.regular };
+        self.layer.use_text(text, size, Mm(x), Mm(self.y), font);
+    }
+
+    fn row(&mut self, cells: [&str; 3], bold: bool) {
+        self.advance(LINE_HEIGHT);
+        for (cell, x) in cells.into_iter().zip(COLUMNS) {
+            self.write_at(cell, 10.0, x, bold);
+        }
+    }
+
+    fn rule(&mut self) {
+        self.advance(2.0);
+        self.layer.set_outline_thickness(0.5);
+        self.layer.add_line(Line {
+            points: vec![
+                (Point::new(Mm(MARGIN), Mm(self.y)), false),
+                (Point::new(Mm(PAGE_WIDTH - MARGIN), Mm(self.y)), false),
+            ],
+            is_closed: false,
+        });
+    }
+}
+
+fn render(
+    template: &ReportTemplate,
+    assets: &Assets,
+    record: &SessionRecord,
+    trainee: Option<&Trainee>,
+) -> Result<Vec<u8>, HistoryError> {
+    let labels = &template.labels;
+    let mut page = PageWriter::new(&template.title, assets.font.as_deref())?;
+
+    if let Some(logo) = &assets.logo {
+        // the dpi scales the image to `LOGO_HEIGHT`
+        let dpi = logo.height() as f32 * 25.4 / LOGO_HEIGHT;
+        Image::from_dynamic_image(logo).add_to_layer(
+            page.layer.clone(),
+            ImageTransform {
+                translate_x: Some(Mm(PAGE_WIDTH - MARGIN - logo.width() as f32 * 25.4 / dpi)),
+                translate_y: Some(Mm(PAGE_HEIGHT - MARGIN - LOGO_HEIGHT)),
+                dpi: Some(dpi),
+                ..Default::default()
+            },
+        );
+    }
+
+    page.text(&template.title, 18.0, true);
+    for line in &template.header_lines {
+        page.advance(1.0);
+        page.text(line, 10.0, false);
+    }
+    if assets.logo.is_some() {
+        page.y = page.y.min(PAGE_HEIGHT - MARGIN - LOGO_HEIGHT);
+    }
+
+    let passed = template.passed(record);
+    page.advance(4.0);
+    page.text(
+        &format!("{}: {}", labels.trainee, record.meta.user_id),
+        12.0,
+        false,
+    );
+    if let Some(trainee) = trainee {
+        page.advance(1.0);
+        page.text(&format!("{}: {}", labels.name, trainee.name), 12.0, false);
+        if let Some(group) = &trainee.group {
+            page.advance(1.0);
+            page.text(&format!("{}: {}", labels.group, group), 12.0, false);
+        }
+    }
+    page.advance(1.0);
+    page.text(
+        &format!(
+            "{}: {}",
+            labels.received_at,
+            history_store::utc_timestamp(record.meta.received_at)
+        ),
+        12.0,
+        false,
+    );
+    page.advance(1.0);
+    page.text(
+        &format!(
+            "{}: {}",
+            labels.result,
+            if passed { &labels.pass } else { &labels.fail }
+        ),
+        12.0,
+        true,
+    );
+
+    for mission in &record.history.mission_datas {
+        let (score, time, passed) = template.grade(mission);
+        page.advance(6.0);
+        page.text(
+            &format!(
+                "{} ({}) {}",
+                mission.name,
+                if mission.complete {
+                    &labels.complete
+                } else {
+                    &labels.incomplete
+                },
+                if passed { &labels.pass } else { &labels.fail }
+            ),
+            13.0,
+            true,
+        );
+        page.row([&labels.stage, &labels.score, &labels.time], true);
+        page.rule();
+        for stage in &mission.stg_datas {
+            page.row(
+                [
+                    &stage.stg_name,
+                    &stage.score.to_string(),
+                    &format!("{:.2}", stage.time),
+                ],
+                false,
+            );
+        }
+        page.rule();
+        page.row(
+            [&labels.total, &score.to_string(), &format!("{:.2}", time)],
+            true,
+        );
+    }
+
+    if let Some(footer) = &template.footer {
+        page.advance(8.0);
+        page.text(footer, 9.0, false);
+    }
+
+    page.doc.save_to_bytes().map_err(export_error)
+}
+
+fn export_error(e: printpdf::Error) -> HistoryError {
+    HistoryError::Export(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history_store::SessionMeta;
+    use crate::play_history::PlayHistory;
+
+    /// A mission of two stages scoring `scores`, 30 seconds each.
+    fn mission(name: &str, complete: bool, scores: [f64; 2]) -> serde_json::Value {
+        serde_json::json!({
+            "name": name,
+            "time": 60.0,
+            "complete": complete,
+            "stgDatas": [
+                {"stgName": "a", "score": scores[0], "time": 30.0},
+                {"stgName": "b", "score": scores[1], "time": 30.5},
+            ],
+        })
+    }
+
+    fn record(missions: Vec<serde_json::Value>) -> SessionRecord {
+        let json = serde_json::json!({"userId": "u1", "missionDatas": missions});
+        SessionRecord {
+            meta: SessionMeta {
+                session_id: "s1".into(),
+                user_id: "u1".into(),
+                received_at: 0,
+                source: None,
+                hash: String::new(),
+            },
+            history: PlayHistory::from_json(&json.to_string()).unwrap(),
+        }
+    }
+
+    fn template(pass_score: f64) -> ReportTemplate {
+        ReportTemplate {
+            pass_score,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn grade_totals_stage_scores_and_times() {
+        let record = record(vec![mission("Fire", true, [3.0, 4.5])]);
+        let grade = template(7.5).grade(&record.history.mission_datas[0]);
+        assert_eq!(grade, (7.5, 60.5, true));
+
+        let grade = template(8.0).grade(&record.history.mission_datas[0]);
+        assert_eq!(grade, (7.5, 60.5, false));
+    }
+
+    #[test]
+    fn mission_pass_scores_override_the_default() {
+        let mut template = template(5.0);
+        template.mission_pass_scores.insert("Fire".into(), 9.0);
+        let record = record(vec![
+            mission("Fire", true, [4.0, 4.0]),
+            mission("Flood", true, [4.0, 4.0]),
+        ]);
+
+        let passed: Vec<bool> = record
+            .history
+            .mission_datas
+            .iter()
+            .map(|mission| template.grade(mission).2)
+            .collect();
+        assert_eq!(passed, [false, true]);
+        assert!(!template.passed(&record));
+
+        template.mission_pass_scores.insert("Fire".into(), 8.0);
+        assert!(template.passed(&record));
+    }
+
+    #[test]
+    fn incomplete_missions_fail_only_when_required() {
+        let mut template = template(0.0);
+        let record = record(vec![mission("Fire", false, [5.0, 5.0])]);
+        assert!(!template.passed(&record));
+
+        template.require_complete = false;
+        assert!(template.passed(&record));
+    }
+
+    #[test]
+    fn session_without_missions_fails() {
+        assert!(!template(0.0).passed(&record(vec![])));
+    }
+
+    #[test]
+    fn renders_a_pdf_with_the_builtin_font() {
+        let record = record(vec![mission("Fire", true, [3.0, 4.5])]);
+        let assets = Assets {
+            logo: None,
+            font: None,
+        };
+
+        let pdf = render(&template(5.0), &assets, &record, None).unwrap();
+        assert!(pdf.starts_with(b"%PDF"));
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::history_store::SessionRecord;
+
+/// Number of stages listed in [`HistoryStats::hardest_stages`].
+const HARDEST_STAGES: usize = 5;
+
+/// Aggregates over a set of stored sessions, shaped for charts.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct HistoryStats {
+    pub sessions: usize,
+    pub users: usize,
+    /// By mission name.
+    pub missions: Vec<MissionStats>,
+    /// By stage name, across missions.
+    pub stages: Vec<StageStats>,
+    /// Stages with the lowest [`StageStats::relative_score`], hardest first.
+    pub hardest_stages: Vec<StageStats>,
+    /// By user id.
+    pub users_progress: Vec<UserProgress>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct MissionStats {
+    pub name: String,
+    pub attempts: usize,
+    pub completed: usize,
+    /// `completed / attempts`, between 0 and 1.
+    pub completion_rate: f64,
+    /// Total stage score of an attempt.
+    pub score: Summary,
+    pub time: Summary,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct StageStats {
+    pub stg_name: String,
+    pub attempts: usize,
+    pub score: Summary,
+    pub time: Summary,
+    /// Mean score over the best score anyone reached, so stages with different
+    /// scales compare. `None` when no attempt scored above 0.
+    pub relative_score: Option<f64>,
+}
+
+/// Distribution of one value, percentiles are linearly interpolated.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Summary {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub p25: f64,
+    pub p75: f64,
+    pub p90: f64,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct UserProgress {
+    pub user_id: String,
+    pub sessions: usize,
+    /// By mission name.
+    pub missions: Vec<MissionProgress>,
+}
+
+/// Every attempt of one user at one mission, oldest first.
+#[derive(Serialize, Clone, Debug)]
+pub struct MissionProgress {
+    pub mission: String,
+    pub attempts: Vec<Attempt>,
+    /// Latest minus first score, `None` with a single attempt.
+    pub score_change: Option<f64>,
+    /// Latest minus first time, negative when the user got faster.
+    pub time_change: Option<f64>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Attempt {
+    pub session_id: String,
+    /// Unix seconds.
+    pub received_at: u64,
+    pub score: f64,
+    pub time: f64,
+    pub complete: bool,
+}
+
+impl Summary {
+    fn of(mut values: Vec<f64>) -> Self {
+        values.retain(|value| value.is_finite());
+        if values.is_empty() {
+            return Self::default();
+        }
+        values.sort_by(f64::total_cmp);
+
+        Self {
+            count: values.len(),
+            min: values[0],
+            max: values[values.len() - 1],
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            median: percentile(&values, 0.5),
+            p25: percentile(&values, 0.25),
+            p75: percentile(&values, 0.75),
+            p90: percentile(&values, 0.9),
+        }
+    }
+}
+
+/// Values collected for one mission or stage.
+#[derive(Default)]
+struct Tally {
+    attempts: usize,
+    completed: usize,
+    scores: Vec<f64>,
+    times: Vec<f64>,
+}
+
+#[derive(Default)]
+struct UserTally<'a> {
+    sessions: usize,
+    attempts: BTreeMap<&'a str, Vec<Attempt>>,
+}
+
+/// `rank` between 0 and 1 of sorted, non-empty `values`.
+fn percentile(values: &[f64], rank: f64) -> f64 {
+    let at = rank * (values.len() - 1) as f64;
+    let below = at.floor() as usize;
+    let above = at.ceil() as usize;
+    values[below] + (values[above] - values[below]) * (at - below as f64)
+}
+
+/// Aggregates `records`, a mission score being the sum of its stage scores.
+pub fn compute(records: &[SessionRecord]) -> HistoryStats {
+    let mut missions: BTreeMap<&str, Tally> = BTreeMap::new();
+    let mut stages: BTreeMap<&str, Tally> = BTreeMap::new();
+    let mut users: BTreeMap<&str, UserTally> = BTreeMap::new();
+
+    for record in records {
+        let user = users.entry(&record.meta.user_id).or_default();
+        user.sessions += 1;
+
+        for mission in &record.history.mission_datas {
+            let score: f64 = mission.stg_datas.iter().map(|stage| stage.score).sum();
+
+            let tally = missions.entry(&mission.name).or_default();
+            tally.attempts += 1;
+            tally.completed += mission.complete as usize;
+            tally.scores.push(score);
+            tally.times.push(mission.time);
+
+            for stage in &mission.stg_datas {
+                let tally = stages.entry(&stage.stg_name).or_default();
+                tally.attempts += 1;
+                tally.scores.push(stage.score);
+                tally.times.push(stage.time);
+            }
+
+            user.attempts
+                .entry(&mission.name)
+                .or_default()
+                .push(Attempt {
+                    session_id: record.meta.session_id.clone(),
+                    received_at: record.meta.received_at,
+                    score,
+                    time: mission.time,
+                    complete: mission.complete,
+                });
+        }
+    }
+
+    let missions = missions
+        .into_iter()
+        .map(|(name, tally)| MissionStats {
+            name: name.to_owned(),
+            attempts: tally.attempts,
+            completed: tally.completed,
+            completion_rate: tally.completed as f64 / tally.attempts as f64,
+            score: Summary::of(tally.scores),
+            time: Summary::of(tally.times),
+        })
+        .collect();
+
+    let stages: Vec<StageStats> = stages
+        .into_iter()
+        .map(|(stg_name, tally)| {
+            let score = Summary::of(tally.scores);
+            StageStats {
+                stg_name: stg_name.to_owned(),
+                attempts: tally.attempts,
+                relative_score: (score.max > 0.0).then(|| score.mean / score.max),
+                score,
+                time: Summary::of(tally.times),
+            }
+        })
+        .collect();
+
+    let mut hardest_stages: Vec<StageStats> = stages
+        .iter()
+        .filter(|stage| stage.relative_score.is_some())
+        .cloned()
+        .collect();
+    hardest_stages.sort_by(|a, b| {
+        a.relative_score
+            .unwrap_or_default()
+            .total_cmp(&b.relative_score.unwrap_or_default())
+            .then(b.time.mean.total_cmp(&a.time.mean))
+    });
+    hardest_stages.truncate(HARDEST_STAGES);
+
+    let users_progress: Vec<UserProgress> = users
+        .into_iter()
+        .map(|(user_id, tally)| UserProgress {
+            user_id: user_id.to_owned(),
+            sessions: tally.sessions,
+            missions: tally
+                .attempts
+                .into_iter()
+                .map(|(mission, attempts)| progress(mission, attempts))
+                .collect(),
+        })
+        .collect();
+
+    HistoryStats {
+        sessions: records.len(),
+        users: users_progress.len(),
+        missions,
+        stages,
+        hardest_stages,
+        users_progress,
+    }
+}
+
+fn progress(mission: &str, mut attempts: Vec<Attempt>) -> MissionProgress {
+    attempts.sort_by(|a, b| {
+        a.received_at
+            .cmp(&b.received_at)
+            .then(a.session_id.cmp(&b.session_id))
+    });
+
+    let change = |value: fn(&Attempt) -> f64| match (attempts.first(), attempts.last()) {
+        (Some(first), Some(last)) if attempts.len() > 1 => Some(value(last) - value(first)),
+        _ => None,
+    };
+
+    MissionProgress {
+        mission: mission.to_owned(),
+        score_change: change(|attempt| attempt.score),
+        time_change: change(|attempt| attempt.time),
+        attempts,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history_store::SessionMeta;
+    use crate::play_history::PlayHistory;
+
+    /// A session whose missions are `(name, time, complete, [(stage, score, time)])`.
+    fn record(
+        user_id: &str,
+        session_id: &str,
+        received_at: u64,
+        missions: &[(&str, f64, bool, &[(&str, f64, f64)])],
+    ) -> SessionRecord {
+        let missions: Vec<_> = missions
+            .iter()
+            .map(|(name, time, complete, stages)| {
+                let stages: Vec<_> = stages
+                    .iter()
+                    .map(|(name, score, time)| {
+                        serde_json::json!({"stgName": name, "score": score, "time": time})
+                    })
+                    .collect();
+                serde_json::json!({
+                    "name": name,
+                    "time": time,
+                    "complete": complete,
+                    "stgDatas": stages,
+                })
+            })
+            .collect();
+        let json = serde_json::json!({"userId": user_id, "missionDatas": missions});
+
+        SessionRecord {
+            meta: SessionMeta {
+                session_id: session_id.into(),
+                user_id: user_id.into(),
+                received_at,
+                source: None,
+                hash: String::new(),
+            },
+            history: PlayHistory::from_json(&json.to_string()).unwrap(),
+        }
+    }
+
+    /// u1 plays Fire twice, getting better and faster, u2 plays Fire and Flood once.
+    fn fixture() -> Vec<SessionRecord> {
+        vec![
+            record(
+                "u1",
+                "s2",
+                200,
+                &[(
+                    "Fire",
+                    70.0,
+                    true,
+                    &[("aim", 6.0, 30.0), ("hose", 4.0, 40.0)],
+                )],
+            ),
+            record(
+                "u2",
+                "s3",
+                150,
+                &[
+                    (
+                        "Fire",
+                        90.0,
+                        true,
+                        &[("aim", 4.0, 45.0), ("hose", 5.0, 45.0)],
+                    ),
+                    (
+                        "Flood",
+                        40.0,
+                        true,
+                        &[("pump", 0.0, 20.0), ("valve", 2.0, 20.0)],
+                    ),
+                ],
+            ),
+            record(
+                "u1",
+                "s1",
+                100,
+                &[(
+                    "Fire",
+                    100.0,
+                    false,
+                    &[("aim", 2.0, 50.0), ("hose", 3.0, 50.0)],
+                )],
+            ),
+        ]
+    }
+
+    #[test]
+    fn percentile_interpolates_between_ranks() {
+        let values = [1.0, 2.0, 4.0, 8.0];
+        assert_eq!(percentile(&values, 0.0), 1.0);
+        assert_eq!(percentile(&values, 1.0), 8.0);
+        assert_eq!(percentile(&values, 0.5), 3.0);
+        assert_eq!(percentile(&values, 0.25), 1.75);
+        assert!((percentile(&values, 0.9) - 6.8).abs() < 1e-9);
+    }
+
+    #[test]
+    fn percentile_of_a_single_value() {
+        assert_eq!(percentile(&[5.0], 0.0), 5.0);
+        assert_eq!(percentile(&[5.0], 0.9), 5.0);
+    }
+
+    #[test]
+    fn summary_skips_non_finite_values() {
+        let summary = Summary::of(vec![3.0, f64::NAN, 1.0, f64::INFINITY, 2.0]);
+        assert_eq!(summary.count, 3);
+        assert_eq!((summary.min, summary.max), (1.0, 3.0));
+        assert_eq!(summary.mean, 2.0);
+        assert_eq!(summary.median, 2.0);
+        assert_eq!(Summary::of(Vec::new()).count, 0);
+    }
+
+    #[test]
+    fn missions_count_attempts_and_completions() {
+        let stats = compute(&fixture());
+        assert_eq!((stats.sessions, stats.users), (3, 2));
+
+        let fire = &stats.missions[0];
+        assert_eq!(fire.name, "Fire");
+        assert_eq!((fire.attempts, fire.completed), (3, 2));
+        assert!((fire.completion_rate - 2.0 / 3.0).abs() < 1e-9);
+        assert_eq!((fire.score.min, fire.score.max), (5.0, 10.0));
+        assert_eq!(fire.time.median, 90.0);
+
+        let flood = &stats.missions[1];
+        assert_eq!((flood.name.as_str(), flood.completion_rate), ("Flood", 1.0));
+    }
+
+    #[test]
+    fn hardest_stages_come_first() {
+        let stats = compute(&fixture());
+
+        let hardest: Vec<(&str, f64)> = stats
+            .hardest_stages
+            .iter()
+            .map(|stage| (stage.stg_name.as_str(), stage.relative_score.unwrap()))
+            .collect();
+        // aim averages 4 of a best 6, hose 4 of 5, valve its only score
+        assert_eq!(hardest, [("aim", 4.0 / 6.0), ("hose", 0.8), ("valve", 1.0)]);
+
+        // nobody scored on pump, so it can't be ranked
+        let pump = stats.stages.iter().find(|stage| stage.stg_name == "pump");
+        assert_eq!(pump.unwrap().relative_score, None);
+    }
+
+    #[test]
+    fn progress_compares_the_latest_attempt_with_the_first() {
+        let stats = compute(&fixture());
+
+        let u1 = &stats.users_progress[0];
+        assert_eq!((u1.user_id.as_str(), u1.sessions), ("u1", 2));
+        let fire = &u1.missions[0];
+        let sessions: Vec<&str> = fire
+            .attempts
+            .iter()
+            .map(|attempt| attempt.session_id.as_str())
+            .collect();
+        assert_eq!(sessions, ["s1", "s2"]);
+        assert_eq!(fire.score_change, Some(5.0));
+        assert_eq!(fire.time_change, Some(-30.0));
+
+        let u2 = &stats.users_progress[1];
+        assert_eq!(u2.missions.len(), 2);
+        assert_eq!(
+            (u2.missions[0].score_change, u2.missions[0].time_change),
+            (None, None)
+        );
+    }
+}
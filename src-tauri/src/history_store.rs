@@ -0,0 +1,641 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::play_history::{HistoryError, HistoryFileReport, PlayHistory};
+
+/// Sub directory of `history_dir` holding one folder of sessions per user.
+const SESSIONS_DIR: &str = "sessions";
+
+/// Sub directory of `history_dir` the integrity scan moves unparsable files to.
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Sub directory of `history_dir` pre-session files are moved to once stored as sessions.
+const IMPORTED_DIR: &str = "imported";
+
+/// Extension of a file still being written, renamed to its final name once synced.
+const TEMP_EXTENSION: &str = "tmp";
+
+/// Hex digits of the content hash used in session ids.
+const SESSION_HASH_LEN: usize = 16;
+
+/// Where and when one play history was received.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionMeta {
+    pub session_id: String,
+    pub user_id: String,
+    /// Unix seconds.
+    pub received_at: u64,
+    /// The headset that pushed it, `None` for imported files.
+    pub source: Option<SocketAddr>,
+    /// SHA-256 of the history, identical pushes share it.
+    pub hash: String,
+}
+
+/// A stored session file, the history wrapped with its metadata.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionRecord {
+    #[serde(flatten)]
+    pub meta: SessionMeta,
+    pub history: PlayHistory,
+}
+
+/// Outcome of [`scan_integrity`].
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct IntegrityReport {
+    /// Unix seconds.
+    pub scanned_at: u64,
+    /// Number of history files that were parsed.
+    pub checked: usize,
+    /// Leftovers of writes interrupted by a crash, deleted. Only the startup scan removes them.
+    pub removed_temp_files: Vec<String>,
+    pub quarantined: Vec<QuarantinedFile>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct QuarantinedFile {
+    pub path: String,
+    pub moved_to: String,
+    pub error: HistoryError,
+}
+
+/// Outcome of [`reassign_sessions`].
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct ReassignReport {
+    pub moved: usize,
+    /// Sessions the new user already had, the old copy was removed.
+    pub duplicates: usize,
+    pub failed: Vec<HistoryFileReport>,
+    /// Both user ids have a roster entry, the one of the old id was left in place.
+    pub roster_conflict: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct SavedSession {
+    pub meta: SessionMeta,
+    pub path: PathBuf,
+    /// The same content was already stored, nothing was written.
+    pub duplicate: bool,
+}
+
+/// Stores `history` as a new session unless the user already has one with the same content.
+pub async fn save_session(
+    history_dir: &Path,
+    history: PlayHistory,
+    source: Option<SocketAddr>,
+    received_at: u64,
+) -> Result<SavedSession, HistoryError> {
+    let hash = content_hash(&history)?;
+    let dir = user_dir(history_dir, &history.user_id);
+
+    for (path, record) in read_sessions(&dir).await? {
+        if record.meta.hash == hash && record.meta.user_id == history.user_id {
+            return Ok(SavedSession {
+                meta: record.meta,
+                path,
+                duplicate: true,
+            });
+        }
+    }
+
+    let meta = SessionMeta {
+        session_id: format!("{}-{}", received_at, &hash[..SESSION_HASH_LEN]),
+        user_id: history.user_id.clone(),
+        received_at,
+        source,
+        hash,
+    };
+    let path = dir.join(format!("{}.json", meta.session_id));
+    let record = SessionRecord { meta, history };
+
+    let json = serde_json::to_string(&record).map_err(|e| HistoryError::Schema(e.to_string()))?;
+    // a session is never overwritten
+    if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        return Err(HistoryError::Io(format!(
+            "{} already exists",
+            path.display()
+        )));
+    }
+    write_atomic(&path, json.as_bytes()).await?;
+
+    Ok(SavedSession {
+        meta: record.meta,
+        path,
+        duplicate: false,
+    })
+}
+
+/// Every stored session of `user_id`, oldest first.
+pub async fn list_sessions(
+    history_dir: &Path,
+    user_id: &str,
+) -> Result<Vec<SessionMeta>, HistoryError> {
+    let mut sessions: Vec<SessionMeta> = read_sessions(&user_dir(history_dir, user_id))
+        .await?
+        .into_iter()
+        .map(|(_, record)| record.meta)
+        .filter(|meta| meta.user_id == user_id)
+        .collect();
+
+    sessions.sort_by(|a, b| {
+        a.received_at
+            .cmp(&b.received_at)
+            .then(a.session_id.cmp(&b.session_id))
+    });
+    Ok(sessions)
+}
+
+pub async fn load_session(
+    history_dir: &Path,
+    user_id: &str,
+    session_id: &str,
+) -> Result<SessionRecord, HistoryError> {
+    let path = session_path(history_dir, user_id, session_id);
+    load_session_file(&path).await
+}
+
+pub async fn load_session_file(path: &Path) -> Result<SessionRecord, HistoryError> {
+    let json = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| HistoryError::Io(format!("{}: {}", path.display(), e)))?;
+    serde_json::from_str(&json).map_err(|e| HistoryError::Schema(e.to_string()))
+}
+
+pub fn session_path(history_dir: &Path, user_id: &str, session_id: &str) -> PathBuf {
+    user_dir(history_dir, user_id).join(format!("{}.json", file_name(session_id)))
+}
+
+/// Moves every session of `from` to `to`, e.g. after a `userId` was mistyped in the headset.
+///
+/// Each session is stored again under the new user id, keeping its receive time and
+/// source, before the old file is removed. Pre-session files are not looked at, they have
+/// to be migrated first. The index has to be synced afterwards.
+pub async fn reassign_sessions(history_dir: &Path, from: &str, to: &str) -> ReassignReport {
+    let mut report = ReassignReport::default();
+    let sessions = match read_sessions(&user_dir(history_dir, from)).await {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            report.failed.push(HistoryFileReport {
+                path: user_dir(history_dir, from).display().to_string(),
+                user_id: Some(from.into()),
+                error: Some(e),
+            });
+            return report;
+        }
+    };
+
+    // other users can share the directory when their ids sanitize to the same name
+    for (path, record) in sessions
+        .into_iter()
+        .filter(|(_, record)| record.meta.user_id == from)
+    {
+        let mut history = record.history;
+        history.user_id = to.into();
+
+        let result = match save_session(
+            history_dir,
+            history,
+            record.meta.source,
+            record.meta.received_at,
+        )
+        .await
+        {
+            Ok(saved) => tokio::fs::remove_file(&path)
+                .await
+                .map(|()| saved.duplicate)
+                .map_err(|e| HistoryError::Io(format!("{}: {}", path.display(), e))),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(true) => report.duplicates += 1,
+            Ok(false) => report.moved += 1,
+            Err(e) => report.failed.push(HistoryFileReport {
+                path: path.display().to_string(),
+                user_id: Some(from.into()),
+                error: Some(e),
+            }),
+        }
+    }
+
+    // only succeeds once the directory is empty
+    let _ = tokio::fs::remove_dir(user_dir(history_dir, from)).await;
+    report
+}
+
+/// Paths of every stored session file of every user, without reading them.
+pub async fn session_files(history_dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for dir in user_dirs(history_dir).await {
+        files.extend(
+            dir_files(&dir)
+                .await
+                .into_iter()
+                .filter(|path| has_extension(path, "json")),
+        );
+    }
+    files
+}
+
+/// Writes `data` to a temporary file next to `path`, syncs it and renames it into place,
+/// so `path` holds either the old or the complete new content even after a crash.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> Result<(), HistoryError> {
+    let io_error = |e: std::io::Error| HistoryError::Io(format!("{}: {}", path.display(), e));
+    let dir = path.parent().unwrap_or(Path::new("."));
+    tokio::fs::create_dir_all(dir).await.map_err(io_error)?;
+
+    let temp_path = temp_path(path);
+    let result = async {
+        let mut file = tokio::fs::File::create(&temp_path).await?;
+        tokio::io::AsyncWriteExt::write_all(&mut file, data).await?;
+        file.sync_all().await?;
+        drop(file);
+        tokio::fs::rename(&temp_path, path).await
+    }
+    .await;
+    if let Err(e) = result {
+        let _ = tokio::fs::remove_file(&temp_path).await;
+        return Err(io_error(e));
+    }
+
+    // persists the rename itself, Windows syncs directory entries on its own
+    #[cfg(unix)]
+    if let Ok(dir) = tokio::fs::File::open(dir).await {
+        let _ = dir.sync_all().await;
+    }
+    Ok(())
+}
+
+/// Copies a saved session into `backup_dir`, keeping the newest `keep` sessions per user.
+pub async fn backup_session(
+    backup_dir: &Path,
+    saved: &SavedSession,
+    keep: usize,
+) -> Result<(), HistoryError> {
+    let data = tokio::fs::read(&saved.path)
+        .await
+        .map_err(|e| HistoryError::Io(format!("{}: {}", saved.path.display(), e)))?;
+    let dir = user_dir(backup_dir, &saved.meta.user_id);
+    write_atomic(
+        &dir.join(format!("{}.json", file_name(&saved.meta.session_id))),
+        &data,
+    )
+    .await?;
+
+    let mut backups: Vec<PathBuf> = dir_files(&dir)
+        .await
+        .into_iter()
+        .filter(|path| has_extension(path, "json"))
+        .collect();
+    // session file names start with the receive time
+    backups.sort_by(|a, b| b.file_name().cmp(&a.file_name()));
+    for old in backups.iter().skip(keep) {
+        if let Err(e) = tokio::fs::remove_file(old).await {
+            eprintln!("Error removing backup {}: {}", old.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Parses every history file, moving the ones that can't be parsed to the quarantine
+/// directory so they are neither indexed nor skipped silently. Pre-session files are only
+/// quarantined when they are not JSON at all.
+///
+/// With `remove_temp_files` the temporary files of interrupted writes are deleted, which is
+/// only safe before anything writes to `history_dir`, i.e. at startup.
+pub async fn scan_integrity(history_dir: &Path, remove_temp_files: bool) -> IntegrityReport {
+    let mut report = IntegrityReport {
+        scanned_at: unix_now(),
+        ..Default::default()
+    };
+
+    // pre-session files directly in `history_dir` hold a bare play history
+    let mut dirs = vec![(history_dir.to_path_buf(), false)];
+    dirs.extend(
+        user_dirs(history_dir)
+            .await
+            .into_iter()
+            .map(|dir| (dir, true)),
+    );
+
+    for (dir, sessions) in dirs {
+        for path in dir_files(&dir).await {
+            if has_extension(&path, TEMP_EXTENSION) {
+                if !remove_temp_files {
+                    continue;
+                }
+                match tokio::fs::remove_file(&path).await {
+                    Ok(()) => report.removed_temp_files.push(path.display().to_string()),
+                    Err(e) => eprintln!("Error removing {}: {}", path.display(), e),
+                }
+                continue;
+            }
+            if !has_extension(&path, "json") {
+                continue;
+            }
+
+            // unreadable files may just be locked, only unparsable ones are quarantined
+            let Ok(data) = tokio::fs::read(&path).await else {
+                continue;
+            };
+            report.checked += 1;
+            // pre-session files only have to be JSON, migrating them reports any other problem
+            let parsed = if sessions {
+                serde_json::from_slice::<SessionRecord>(&data).map(|_| ())
+            } else {
+                serde_json::from_slice::<serde_json::Value>(&data).map(|_| ())
+            };
+            let Err(e) = parsed else {
+                continue;
+            };
+            let error = HistoryError::Schema(e.to_string());
+
+            match quarantine(history_dir, &path, report.scanned_at).await {
+                Ok(moved_to) => report.quarantined.push(QuarantinedFile {
+                    path: path.display().to_string(),
+                    moved_to: moved_to.display().to_string(),
+                    error,
+                }),
+                Err(e) => eprintln!("Error quarantining {}: {}", path.display(), e),
+            }
+        }
+    }
+    report
+}
+
+/// Moves a pre-session `history_dir/*.json` file that is stored as a session to the imported
+/// directory, so it is migrated once and deleted or reassigned sessions don't come back.
+pub async fn archive_legacy_file(history_dir: &Path, path: &Path) -> Result<PathBuf, HistoryError> {
+    let io_error = |e: std::io::Error| HistoryError::Io(format!("{}: {}", path.display(), e));
+    let dir = history_dir.join(IMPORTED_DIR);
+    tokio::fs::create_dir_all(&dir).await.map_err(io_error)?;
+
+    let name = path.file_name().unwrap_or_default();
+    let mut moved_to = dir.join(name);
+    if tokio::fs::try_exists(&moved_to).await.unwrap_or(false) {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        moved_to = dir.join(format!("{}-{}.json", stem, unix_now()));
+    }
+    tokio::fs::rename(path, &moved_to).await.map_err(io_error)?;
+    Ok(moved_to)
+}
+
+async fn quarantine(history_dir: &Path, path: &Path, scanned_at: u64) -> std::io::Result<PathBuf> {
+    let relative = path
+        .strip_prefix(history_dir)
+        .unwrap_or(path)
+        .with_extension("");
+    let dir = history_dir.join(QUARANTINE_DIR);
+    let moved_to = dir.join(format!(
+        "{}-{}.json",
+        scanned_at,
+        file_name(&relative.display().to_string())
+    ));
+
+    tokio::fs::create_dir_all(&dir).await?;
+    tokio::fs::rename(path, &moved_to).await?;
+    Ok(moved_to)
+}
+
+async fn user_dirs(history_dir: &Path) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    let mut entries = match tokio::fs::read_dir(history_dir.join(SESSIONS_DIR)).await {
+        Ok(entries) => entries,
+        Err(_) => return dirs,
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_type().await.is_ok_and(|kind| kind.is_dir()) {
+            dirs.push(entry.path());
+        }
+    }
+    dirs
+}
+
+async fn dir_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(_) => return files,
+    };
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        if entry.file_type().await.is_ok_and(|kind| kind.is_file()) {
+            files.push(entry.path());
+        }
+    }
+    files
+}
+
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension().and_then(|ext| ext.to_str()) == Some(extension)
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(TEMP_EXTENSION);
+    path.with_file_name(name)
+}
+
+async fn read_sessions(dir: &Path) -> Result<Vec<(PathBuf, SessionRecord)>, HistoryError> {
+    let mut entries = match tokio::fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(HistoryError::Io(format!("{}: {}", dir.display(), e))),
+    };
+
+    let mut sessions = Vec::new();
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let path = entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        match load_session_file(&path).await {
+            Ok(record) => sessions.push((path, record)),
+            Err(e) => eprintln!("Skipping session {}: {}", path.display(), e),
+        }
+    }
+    Ok(sessions)
+}
+
+fn content_hash(history: &PlayHistory) -> Result<String, HistoryError> {
+    // the extra fields are a sorted map, so equal histories serialize to equal bytes
+    let bytes = serde_json::to_vec(history).map_err(|e| HistoryError::Schema(e.to_string()))?;
+    Ok(Sha256::digest(&bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+fn user_dir(history_dir: &Path, user_id: &str) -> PathBuf {
+    history_dir.join(SESSIONS_DIR).join(file_name(user_id))
+}
+
+/// Makes an id safe to use as a single path component.
+pub fn file_name(id: &str) -> String {
+    let name: String = id
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    if name.is_empty() {
+        "_".into()
+    } else {
+        name
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// `YYYY-MM-DD hh:mm:ss` of a unix timestamp in UTC.
+pub fn utc_timestamp(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let secs = secs % 86_400;
+
+    // days to civil date, http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory of its own for one test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("history-store-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn history(user_id: &str, extra: &str) -> PlayHistory {
+        let json = format!(
+            r#"{{"userId":"{}","missionDatas":[],"note":"{}"}}"#,
+            user_id, extra
+        );
+        PlayHistory::from_json(&json).unwrap()
+    }
+
+    #[tokio::test]
+    async fn save_session_skips_identical_histories() {
+        let dir = test_dir("dedup");
+
+        let first = save_session(&dir, history("u1", "a"), None, 100)
+            .await
+            .unwrap();
+        let again = save_session(&dir, history("u1", "a"), None, 200)
+            .await
+            .unwrap();
+        assert!(!first.duplicate);
+        assert!(again.duplicate);
+        assert_eq!(again.meta.session_id, first.meta.session_id);
+        assert_eq!(again.path, first.path);
+
+        let other = save_session(&dir, history("u1", "b"), None, 300)
+            .await
+            .unwrap();
+        let other_user = save_session(&dir, history("u2", "a"), None, 100)
+            .await
+            .unwrap();
+        assert!(!other.duplicate);
+        assert!(!other_user.duplicate);
+
+        assert_eq!(list_sessions(&dir, "u1").await.unwrap().len(), 2);
+        assert_eq!(session_files(&dir).await.len(), 3);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn reassign_moves_sessions_and_merges_duplicates() {
+        let dir = test_dir("reassign");
+        save_session(&dir, history("u1", "a"), None, 100)
+            .await
+            .unwrap();
+        save_session(&dir, history("u1", "b"), None, 200)
+            .await
+            .unwrap();
+        save_session(&dir, history("u2", "b"), None, 300)
+            .await
+            .unwrap();
+
+        let report = reassign_sessions(&dir, "u1", "u2").await;
+
+        assert_eq!((report.moved, report.duplicates), (1, 1));
+        assert!(report.failed.is_empty());
+        assert!(list_sessions(&dir, "u1").await.unwrap().is_empty());
+        let moved = list_sessions(&dir, "u2").await.unwrap();
+        assert_eq!(moved.len(), 2);
+        assert!(moved.iter().any(|meta| meta.received_at == 100));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn scan_quarantines_only_broken_json() {
+        let dir = test_dir("scan");
+        std::fs::write(dir.join("unknown.json"), r#"{"userId":7}"#).unwrap();
+        std::fs::write(dir.join("list.json"), "[1, 2]").unwrap();
+        std::fs::write(dir.join("broken.json"), r#"{"userId":"#).unwrap();
+        std::fs::write(dir.join("u1.json.tmp"), "").unwrap();
+        let saved = save_session(&dir, history("u1", "a"), None, 100)
+            .await
+            .unwrap();
+        std::fs::write(saved.path.with_file_name("bad.json"), "{}").unwrap();
+
+        // a write may be in progress, only the startup scan deletes temporary files
+        let report = scan_integrity(&dir, false).await;
+        assert!(report.removed_temp_files.is_empty());
+        assert!(dir.join("u1.json.tmp").exists());
+        assert_eq!(report.quarantined.len(), 2);
+
+        std::fs::write(dir.join("broken.json"), r#"{"userId":"#).unwrap();
+        std::fs::write(saved.path.with_file_name("bad.json"), "{}").unwrap();
+        let report = scan_integrity(&dir, true).await;
+
+        assert_eq!(report.checked, 5);
+        assert_eq!(report.removed_temp_files.len(), 1);
+        let mut quarantined: Vec<&str> = report
+            .quarantined
+            .iter()
+            .map(|file| Path::new(&file.path).file_name().unwrap().to_str().unwrap())
+            .collect();
+        quarantined.sort();
+        assert_eq!(quarantined, ["bad.json", "broken.json"]);
+        assert!(dir.join("unknown.json").exists());
+        assert!(saved.path.exists());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
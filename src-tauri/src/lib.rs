@@ -1,33 +1,74 @@
-use std::{
-    collections::{hash_map::Entry, HashMap},
-    path::PathBuf,
-};
+use std::{collections::HashMap, net::SocketAddr, path::PathBuf, sync::Arc};
 
-use lazy_static::lazy_static;
-use serde_json::Value;
-use tauri::{Emitter, Manager, Runtime, Window};
+use tauri::{AppHandle, Emitter, Manager, Runtime, State, Window};
 use tokio::{
-    fs::{read_dir, File},
-    io::{AsyncReadExt, AsyncWriteExt},
-    sync::RwLock,
+    fs::read_dir,
+    sync::{mpsc, Mutex, RwLock},
 };
 
-use crate::fm_network::{action::FMAction, packet::FMPacket, PLAY_HISTORY_PATH};
+use crate::config::AppConfig;
+use crate::fm_network::{
+    action::{ActionKind, FMAction},
+    command::HeadsetCommand,
+    error::NetworkError,
+    handler::{HistoryRequestOutcome, NetworkStatus, ReceivedHistory, StoredHistory},
+    packet::FMPacket,
+    registry::{DeviceRecord, DeviceUpdate},
+    reliable::CommandOutcome,
+    stream_stats::StreamStats,
+    subscription::{ActionFilter, Subscription},
+    target::{Delivery, Target},
+    FmNetwork,
+};
+use crate::history_db::{HistoryDb, HistoryPage, HistoryQuery, ImportReport};
+use crate::history_export::{ExportFormat, ExportSummary};
+use crate::history_import::{ImportFileReport, ImportStatus};
+use crate::history_report::{ReportOutcome, ReportTemplate};
+use crate::history_stats::HistoryStats;
+use crate::history_store::{
+    IntegrityReport, ReassignReport, SavedSession, SessionMeta, SessionRecord,
+};
+use crate::play_history::{HistoryError, HistoryFileReport, PlayHistory};
+use crate::roster::{Roster, RosterImportReport, Trainee};
 
+mod config;
 mod fm_network;
+mod history_db;
+mod history_export;
+mod history_import;
+mod history_report;
+mod history_stats;
+mod history_store;
+mod play_history;
+mod roster;
 
-lazy_static! {
-    static ref PLAY_HISTORY_CACHE: RwLock<HashMap<String, HashMap<String, Value>>> =
-        RwLock::new(HashMap::new());
-}
+/// One network subscription per window that called `start_udp`.
+#[derive(Default)]
+struct UiSubscriptions(Mutex<HashMap<String, Subscription>>);
 
-#[tauri::command]
-async fn start_udp<R: Runtime>(window: Window<R>) {
-    if !fm_network::run().await {
-        return;
-    }
+/// Trainees linked to play histories by user id.
+struct TraineeRoster(RwLock<Roster>);
 
-    fm_network::listen(move |data| match data {
+/// Result of the last integrity scan, `None` while the startup scan is still running.
+#[derive(Default, Clone)]
+struct IntegrityScan(Arc<Mutex<Option<IntegrityReport>>>);
+
+#[tauri::command]
+async fn start_udp<R: Runtime>(
+    window: Window<R>,
+    network: State<'_, FmNetwork>,
+    subscriptions: State<'_, UiSubscriptions>,
+) -> Result<SocketAddr, NetworkError> {
+    let label = window.label().to_owned();
+    let filter = ActionFilter::all().kinds([
+        ActionKind::ClientChanged,
+        ActionKind::JpegDecoded,
+        ActionKind::HistoryTransferFailed,
+        ActionKind::MalformedPacket,
+        ActionKind::StreamStats,
+        ActionKind::DeviceIdentified,
+    ]);
+    let subscription = network.listen(filter, move |data| match data {
         FMAction::ClientChanged(detail) => {
             let _ = window
                 .app_handle()
@@ -39,80 +80,207 @@ async fn start_udp<R: Runtime>(window: Window<R>) {
                 .app_handle()
                 .emit_to(window.label(), "fm://jpeg_decoded", detail);
         }
-        FMAction::HistoryReceived(detail) => {
-            let map = detail.map.to_owned();
-            let id = detail.player_id.to_owned();
-            let value = window.clone();
-            let _ = tokio::task::spawn(async move {
-                if let Some(path) = save_play_history(&id, &map).await {
-                    let _ = value.app_handle().emit_to(
-                        value.label(),
-                        "fm://history_saved",
-                        (&id, &path),
-                    );
-
-                    let mut cache = PLAY_HISTORY_CACHE.write().await;
-                    cache.insert(id, map);
-                };
-            });
+        FMAction::HistoryTransferFailed(detail) => {
+            let _ =
+                window
+                    .app_handle()
+                    .emit_to(window.label(), "fm://history_transfer_failed", detail);
+        }
+        FMAction::MalformedPacket(detail) => {
+            let _ = window
+                .app_handle()
+                .emit_to(window.label(), "fm://malformed_packet", detail);
+        }
+        FMAction::StreamStats(stats) => {
+            let _ = window
+                .app_handle()
+                .emit_to(window.label(), "fm://stream_stats", stats);
+        }
+        FMAction::DeviceIdentified(detail) => {
+            let _ = window
+                .app_handle()
+                .emit_to(window.label(), "fm://device_identified", detail);
+        }
+        FMAction::Lagged { skipped } => {
+            let _ = window
+                .app_handle()
+                .emit_to(window.label(), "fm://events_lagged", skipped);
         }
         _ => {}
-    })
-    .await;
+    });
+    // replacing the previous handle of this window unsubscribes it
+    subscriptions.0.lock().await.insert(label, subscription);
+
+    network.start().await
 }
 
+/// Emits `fm://watched_client` to the calling window for every action about `addr`.
 #[tauri::command]
-async fn stop_udp() {
-    fm_network::stop().await;
+async fn watch_client<R: Runtime>(
+    addr: SocketAddr,
+    window: Window<R>,
+    network: State<'_, FmNetwork>,
+    subscriptions: State<'_, UiSubscriptions>,
+) -> Result<(), NetworkError> {
+    let key = format!("{}/{}", window.label(), addr);
+    let filter = ActionFilter::all().addr(addr).kinds([
+        ActionKind::ClientChanged,
+        ActionKind::MalformedPacket,
+        ActionKind::DeviceIdentified,
+        ActionKind::HistoryReceived,
+    ]);
+    let subscription = network.listen(filter, move |data| {
+        let _ =
+            window
+                .app_handle()
+                .emit_to(window.label(), "fm://watched_client", (addr, data.kind()));
+    });
+    subscriptions.0.lock().await.insert(key, subscription);
+
+    Ok(())
 }
 
 #[tauri::command]
-async fn send_msg(addr: String, msg: String) {
+async fn unwatch_client<R: Runtime>(
+    addr: SocketAddr,
+    window: Window<R>,
+    subscriptions: State<'_, UiSubscriptions>,
+) -> Result<bool, NetworkError> {
+    let key = format!("{}/{}", window.label(), addr);
+    // dropping the removed handle stops its listener
+    let removed = subscriptions.0.lock().await.remove(&key);
+    Ok(removed.is_some())
+}
+
+#[tauri::command]
+async fn stop_udp(network: State<'_, FmNetwork>) -> Result<(), NetworkError> {
+    network.stop().await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn restart_udp(network: State<'_, FmNetwork>) -> Result<SocketAddr, NetworkError> {
+    network.restart().await
+}
+
+#[tauri::command]
+async fn send_msg(
+    addr: String,
+    msg: String,
+    network: State<'_, FmNetwork>,
+) -> Result<(), NetworkError> {
     dbg!(&addr, &msg);
-    fm_network::send(addr.into(), FMPacket::StringPacket { data: msg }).await;
-}
-
-#[tauri::command]
-async fn query_play_histories() -> Result<String, String> {
-    let mut category = HashMap::<String, String>::new();
-
-    if let Ok(mut r) = read_dir(PLAY_HISTORY_PATH).await {
-        let mut cache = PLAY_HISTORY_CACHE.write().await;
-        let mut content = String::new();
-
-        while let Ok(Some(dir_entry)) = r.next_entry().await {
-            if let Some(path) = dir_entry.path().to_str() {
-                match cache.entry(path.into()) {
-                    Entry::Vacant(entry) => {
-                        if let Ok(mut file) = File::open(path).await {
-                            if let Ok(_) = file.read_to_string(&mut content).await {
-                                if let Ok(map) =
-                                    serde_json::from_str::<HashMap<String, Value>>(&content)
-                                {
-                                    if let Some(id) =
-                                        map.get("userId").map(|v| v.as_str()).flatten()
-                                    {
-                                        category.insert(id.into(), path.into());
-                                        entry.insert(map);
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Entry::Occupied(entry) => {
-                        let v = entry.get();
-                        let k = entry.key();
-                        if let Some(id) = v.get("userId").map(|v| v.as_str()).flatten() {
-                            category.insert(id.into(), k.into());
-                        }
-                    }
-                }
-            }
+    network
+        .send(addr.into(), FMPacket::StringPacket { data: msg })
+        .await
+}
+
+/// Resolves when the headset acknowledges `msg`, or with the reason it never did.
+#[tauri::command]
+async fn send_command(
+    addr: String,
+    msg: String,
+    network: State<'_, FmNetwork>,
+) -> Result<CommandOutcome, NetworkError> {
+    Ok(network.send_reliable(addr.into(), msg).await)
+}
+
+/// Sends `msg` to a whole room at once and reports what happened per headset.
+#[tauri::command]
+async fn send_to_targets(
+    target: Target,
+    msg: String,
+    reliable: bool,
+    network: State<'_, FmNetwork>,
+) -> Result<Vec<Delivery>, NetworkError> {
+    network.send_to(target, msg, reliable).await
+}
 
-            content.clear();
+/// Sends a typed control command, unknown commands or arguments are rejected before sending.
+#[tauri::command]
+async fn send_headset_command(
+    target: Target,
+    command: HeadsetCommand,
+    reliable: bool,
+    network: State<'_, FmNetwork>,
+) -> Result<Vec<Delivery>, NetworkError> {
+    command.validate()?;
+    network.send_to(target, command.to_wire(), reliable).await
+}
+
+/// Asks a headset for the histories it stored, e.g. ones pushed while the controller was offline.
+#[tauri::command]
+async fn request_history(
+    addr: String,
+    network: State<'_, FmNetwork>,
+) -> Result<HistoryRequestOutcome, NetworkError> {
+    Ok(network.request_history(addr.into()).await)
+}
+
+#[tauri::command]
+async fn network_status(network: State<'_, FmNetwork>) -> Result<NetworkStatus, NetworkError> {
+    Ok(network.status().await)
+}
+
+#[tauri::command]
+async fn get_stream_stats(network: State<'_, FmNetwork>) -> Result<Vec<StreamStats>, NetworkError> {
+    Ok(network.stream_stats().await)
+}
+
+#[tauri::command]
+async fn list_devices(network: State<'_, FmNetwork>) -> Result<Vec<DeviceRecord>, NetworkError> {
+    Ok(network.list_devices().await)
+}
+
+#[tauri::command]
+async fn rename_device(
+    device_id: String,
+    update: DeviceUpdate,
+    network: State<'_, FmNetwork>,
+) -> Result<DeviceRecord, String> {
+    network
+        .update_device(&device_id, update)
+        .await
+        .ok_or_else(|| format!("Unknown device {}", device_id))
+}
+
+#[tauri::command]
+async fn forget_device(
+    device_id: String,
+    network: State<'_, FmNetwork>,
+) -> Result<bool, NetworkError> {
+    Ok(network.forget_device(&device_id).await)
+}
+
+#[tauri::command]
+async fn get_config() -> AppConfig {
+    config::current().await
+}
+
+#[tauri::command]
+async fn set_config(config: AppConfig) -> Result<AppConfig, String> {
+    config::update(config).await
+}
+
+/// The newest session of every trainee as a `userId -> record id` map for [`get_history`].
+#[tauri::command]
+async fn query_play_histories(db: State<'_, HistoryDb>) -> Result<String, String> {
+    let history_dir = config::current().await.history_dir;
+    let report = db.sync(&history_dir).await;
+    for failed in &report.failed {
+        if let Some(e) = &failed.error {
+            eprintln!("Skipping session {}: {}", failed.path, e);
         }
     }
 
+    let category: HashMap<String, String> = db
+        .latest_per_user()
+        .await
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .map(|meta| (meta.user_id, meta.session_id))
+        .collect();
+
     if let Ok(string) = serde_json::ser::to_string(&category) {
         Ok(string)
     } else {
@@ -120,50 +288,519 @@ async fn query_play_histories() -> Result<String, String> {
     }
 }
 
+/// Sessions matching the filters, newest first, one page at a time.
+#[tauri::command]
+async fn query_sessions(
+    query: HistoryQuery,
+    db: State<'_, HistoryDb>,
+    roster: State<'_, TraineeRoster>,
+) -> Result<HistoryPage, HistoryError> {
+    let mut page = db.query(&query).await?;
+    page.trainees = roster
+        .0
+        .read()
+        .await
+        .lookup(page.sessions.iter().map(|meta| meta.user_id.as_str()));
+    Ok(page)
+}
+
+/// Brings the index in line with the history directory, e.g. after files were copied in by hand.
+#[tauri::command]
+async fn import_play_histories(db: State<'_, HistoryDb>) -> Result<ImportReport, HistoryError> {
+    let history_dir = config::current().await.history_dir;
+    Ok(db.import_dir(&history_dir).await)
+}
+
+/// Imports the histories in a JSON file, a zip or a folder of either, e.g. copied off a
+/// headset used offline. Every history is stored like a received one, with the problems
+/// validation found reported next to it.
+#[tauri::command]
+async fn import_history_files(
+    path: PathBuf,
+    db: State<'_, HistoryDb>,
+) -> Result<Vec<ImportFileReport>, HistoryError> {
+    let mut reports = Vec::new();
+    for candidate in history_import::collect(&path).await? {
+        let result = match &candidate.history {
+            Ok(history) => store_play_history(history, None, candidate.received_at, &db).await,
+            Err(e) => Err(e.clone()),
+        };
+
+        let problems = candidate
+            .history
+            .as_ref()
+            .ok()
+            .and_then(|history| history.validate().err());
+        let user_id = candidate.history.ok().map(|history| history.user_id);
+        reports.push(match result {
+            Ok(saved) => ImportFileReport {
+                file: candidate.file,
+                status: if saved.duplicate {
+                    ImportStatus::Duplicate
+                } else {
+                    ImportStatus::Imported
+                },
+                user_id,
+                session_id: Some(saved.meta.session_id),
+                error: None,
+                problems,
+            },
+            Err(e) => ImportFileReport {
+                file: candidate.file,
+                status: ImportStatus::Failed,
+                user_id,
+                session_id: None,
+                error: Some(e),
+                problems,
+            },
+        });
+    }
+    Ok(reports)
+}
+
+/// Writes the sessions matching `query` to `path` as one row per stage. The format
+/// defaults to the file extension; paging in `query` is ignored.
+#[tauri::command]
+async fn export_play_histories(
+    path: PathBuf,
+    format: Option<ExportFormat>,
+    query: HistoryQuery,
+    db: State<'_, HistoryDb>,
+    roster: State<'_, TraineeRoster>,
+) -> Result<ExportSummary, HistoryError> {
+    let format = ExportFormat::resolve(format, &path)?;
+    let history_dir = config::current().await.history_dir;
+    db.sync(&history_dir).await;
+
+    let records = db.records(&query).await?;
+    let roster = roster.0.read().await;
+    history_export::export(&records, &roster, &path, format).await
+}
+
+/// Completion rates, score and time distributions and per-user progress over the
+/// sessions matching `query`. Paging in `query` is ignored.
+#[tauri::command]
+async fn get_history_stats(
+    query: HistoryQuery,
+    db: State<'_, HistoryDb>,
+) -> Result<HistoryStats, HistoryError> {
+    let history_dir = config::current().await.history_dir;
+    db.sync(&history_dir).await;
+
+    let records = db.records(&query).await?;
+    Ok(history_stats::compute(&records))
+}
+
+#[tauri::command]
+async fn get_report_template() -> Result<ReportTemplate, String> {
+    let path = config::current().await.report_template_path;
+    Ok(ReportTemplate::load(&path).await)
+}
+
+#[tauri::command]
+async fn set_report_template(template: ReportTemplate) -> Result<ReportTemplate, String> {
+    let path = config::current().await.report_template_path;
+    template.save(&path).await?;
+    Ok(template)
+}
+
+/// Renders the session `key` resolves to (see [`get_history`]) into a PDF at `path`.
+#[tauri::command]
+async fn generate_report(
+    key: String,
+    path: PathBuf,
+    db: State<'_, HistoryDb>,
+    roster: State<'_, TraineeRoster>,
+) -> Result<PathBuf, HistoryError> {
+    let record = db
+        .get(&key)
+        .await?
+        .ok_or_else(|| HistoryError::Io(format!("no play history for {}", key)))?;
+    let template = report_template(&config::current().await).await;
+
+    let trainee = roster.0.read().await.get(&record.meta.user_id).cloned();
+    history_report::generate(&template, &record, trainee.as_ref(), &path).await?;
+    Ok(path)
+}
+
+/// One PDF per session matching `query` into `dir`, e.g. every trainee of a drill
+/// by its time range. Paging in `query` is ignored.
+#[tauri::command]
+async fn generate_reports(
+    query: HistoryQuery,
+    dir: PathBuf,
+    db: State<'_, HistoryDb>,
+    roster: State<'_, TraineeRoster>,
+) -> Result<Vec<ReportOutcome>, HistoryError> {
+    let config = config::current().await;
+    db.sync(&config.history_dir).await;
+    let records = db.records(&query).await?;
+    let template = report_template(&config).await;
+
+    let roster = roster.0.read().await;
+    history_report::generate_batch(&template, &records, &roster, &dir).await
+}
+
+/// The report template to render with, using the configured font if it names none.
+async fn report_template(config: &AppConfig) -> ReportTemplate {
+    let mut template = ReportTemplate::load(&config.report_template_path).await;
+    if template.font_path.is_none() {
+        template.font_path = config.report_font_path.clone();
+    }
+    template
+}
+
+/// Every trainee, or those of one group or class, by group and name.
+#[tauri::command]
+async fn list_trainees(
+    group: Option<String>,
+    roster: State<'_, TraineeRoster>,
+) -> Result<Vec<Trainee>, HistoryError> {
+    Ok(roster.0.read().await.list(group.as_deref()))
+}
+
+/// Adds a trainee or replaces the one with the same user id.
 #[tauri::command]
-async fn get_history(key: String) -> Result<HashMap<String, Value>, ()> {
-    let cache = PLAY_HISTORY_CACHE.read().await;
+async fn save_trainee(
+    trainee: Trainee,
+    roster: State<'_, TraineeRoster>,
+) -> Result<Trainee, HistoryError> {
+    let mut roster = roster.0.write().await;
+    let mut updated = roster.clone();
+    updated
+        .upsert(trainee.clone())
+        .map_err(|e| HistoryError::Invalid(vec![e]))?;
+    updated.save().await?;
+    *roster = updated;
+    Ok(trainee)
+}
 
-    if let Some(data) = cache.get(&key) {
-        return Ok(data.to_owned());
+/// Removes a trainee from the roster, their play histories are kept.
+#[tauri::command]
+async fn remove_trainee(
+    user_id: String,
+    roster: State<'_, TraineeRoster>,
+) -> Result<bool, HistoryError> {
+    let mut roster = roster.0.write().await;
+    let mut updated = roster.clone();
+    let removed = updated.remove(&user_id);
+    if removed {
+        updated.save().await?;
+        *roster = updated;
     }
+    Ok(removed)
+}
+
+/// Adds or updates trainees from a CSV file with `user_id`, `name`, `group` and
+/// `external_id` columns.
+#[tauri::command]
+async fn import_roster(
+    path: PathBuf,
+    roster: State<'_, TraineeRoster>,
+) -> Result<RosterImportReport, HistoryError> {
+    let data = tokio::fs::read(&path)
+        .await
+        .map_err(|e| HistoryError::Io(format!("{}: {}", path.display(), e)))?;
 
-    Err(())
+    let mut roster = roster.0.write().await;
+    let mut updated = roster.clone();
+    let report = updated.import_csv(&data)?;
+    updated.save().await?;
+    *roster = updated;
+    Ok(report)
 }
 
-async fn save_play_history(user_id: &String, map: &HashMap<String, Value>) -> Option<String> {
-    let mut file_path = PathBuf::new();
-    file_path.push(PLAY_HISTORY_PATH);
-    tokio::fs::create_dir_all(&file_path).await.ok();
-    file_path.push(format!("{}.json", user_id));
+/// Moves every session and the roster entry of a mistyped user id to the right one.
+///
+/// A roster entry is only moved if the new id has none, `roster_conflict` reports otherwise.
+#[tauri::command]
+async fn reassign_user_id(
+    from: String,
+    to: String,
+    db: State<'_, HistoryDb>,
+    roster: State<'_, TraineeRoster>,
+) -> Result<ReassignReport, HistoryError> {
+    let (from, to) = (from.trim(), to.trim());
+    if from.is_empty() || to.is_empty() || to == from {
+        return Err(HistoryError::Invalid(vec![
+            "both user ids must be non-empty and differ from each other".into(),
+        ]));
+    }
 
-    if let Ok(json) = serde_json::to_string(&map) {
-        if let Ok(mut file) = File::create(&file_path).await {
-            if let Err(e) = file.write_all(json.as_bytes()).await {
-                eprintln!("Error writing play history to file: {}", e);
-            } else if let Some(path) = file_path.to_str() {
-                println!("Play history saved to file: {}", file_path.display());
+    // pre-session files of `from` become sessions first, or they would be left behind
+    let history_dir = config::current().await.history_dir;
+    db.import_dir(&history_dir).await;
+    let mut report = history_store::reassign_sessions(&history_dir, from, to).await;
+    db.sync(&history_dir).await;
 
-                return Some(path.into());
-            }
-        } else {
-            eprintln!("Error creating play history file: {}", file_path.display());
+    let mut roster = roster.0.write().await;
+    let mut updated = roster.clone();
+    match updated.rename(from, to) {
+        Ok(true) => {
+            updated.save().await?;
+            *roster = updated;
+        }
+        Ok(false) => {}
+        Err(e) => {
+            eprintln!("Roster entry of {} not moved: {}", from, e);
+            report.roster_conflict = true;
         }
     }
+    Ok(report)
+}
+
+/// Every stored session of a trainee, oldest first.
+#[tauri::command]
+async fn list_sessions(user_id: String) -> Result<Vec<SessionMeta>, HistoryError> {
+    let history_dir = config::current().await.history_dir;
+    history_store::list_sessions(&history_dir, &user_id).await
+}
 
-    None
+#[tauri::command]
+async fn get_session(user_id: String, session_id: String) -> Result<SessionRecord, HistoryError> {
+    let history_dir = config::current().await.history_dir;
+    history_store::load_session(&history_dir, &user_id, &session_id).await
+}
+
+/// Parses and validates every stored session, and any legacy file not migrated yet, one
+/// report per file.
+#[tauri::command]
+async fn validate_play_histories() -> Result<Vec<HistoryFileReport>, String> {
+    let history_dir = config::current().await.history_dir;
+    let mut r = read_dir(&history_dir)
+        .await
+        .map_err(|e| format!("Error reading {}: {}", history_dir.display(), e))?;
+
+    let mut reports = Vec::new();
+    while let Ok(Some(dir_entry)) = r.next_entry().await {
+        let path = dir_entry.path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+
+        let history = PlayHistory::load(&path).await;
+        reports.push(HistoryFileReport::new(&path, history.as_ref()));
+    }
+
+    for path in history_store::session_files(&history_dir).await {
+        let record = history_store::load_session_file(&path).await;
+        let history = record.as_ref().map(|record| &record.history);
+        reports.push(HistoryFileReport::new(&path, history));
+    }
+
+    reports.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(reports)
+}
+
+/// Files the last integrity scan moved to quarantine because they could not be parsed.
+#[tauri::command]
+async fn get_integrity_report(
+    scan: State<'_, IntegrityScan>,
+) -> Result<Option<IntegrityReport>, HistoryError> {
+    Ok(scan.0.lock().await.clone())
+}
+
+/// Scans the history directory again and brings the index in line with the result.
+#[tauri::command]
+async fn scan_play_histories(
+    scan: State<'_, IntegrityScan>,
+    db: State<'_, HistoryDb>,
+) -> Result<IntegrityReport, HistoryError> {
+    let history_dir = config::current().await.history_dir;
+    // histories may be mid-write, so temporary files are left alone
+    let report = history_store::scan_integrity(&history_dir, false).await;
+    db.sync(&history_dir).await;
+
+    *scan.0.lock().await = Some(report.clone());
+    Ok(report)
+}
+
+/// Looks a history up by record id, session file path or user id (newest session).
+#[tauri::command]
+async fn get_history(key: String, db: State<'_, HistoryDb>) -> Result<PlayHistory, HistoryError> {
+    match db.get(&key).await? {
+        Some(record) => Ok(record.history),
+        None => Err(HistoryError::Io(format!("no play history for {}", key))),
+    }
+}
+
+/// Stores every history the network receives, in order, and tells all windows about new sessions.
+async fn store_received_histories<R: Runtime>(
+    mut histories: mpsc::UnboundedReceiver<ReceivedHistory>,
+    db: &HistoryDb,
+    app: &AppHandle<R>,
+) {
+    while let Some(received) = histories.recv().await {
+        let saved = save_play_history(&received.history, received.addr, db).await;
+        received.confirm(
+            saved
+                .as_ref()
+                .map(|saved| StoredHistory {
+                    user_id: saved.meta.user_id.clone(),
+                    session_id: saved.meta.session_id.clone(),
+                    duplicate: saved.duplicate,
+                })
+                .map_err(|e| e.to_string()),
+        );
+
+        if let Some(saved) = saved.ok().filter(|saved| !saved.duplicate) {
+            let path = saved.path.display().to_string();
+            let _ = app.emit("fm://history_saved", (&saved.meta.user_id, &path));
+        }
+    }
+}
+
+/// Stores a history received from `source` and logs the outcome, a duplicate push comes back
+/// with `duplicate` set.
+async fn save_play_history(
+    history: &PlayHistory,
+    source: SocketAddr,
+    db: &HistoryDb,
+) -> Result<SavedSession, HistoryError> {
+    let received_at = history_store::unix_now();
+    let saved = store_play_history(history, Some(source), received_at, db).await;
+
+    match &saved {
+        Ok(saved) if saved.duplicate => println!(
+            "Play history of {} already stored as {}",
+            saved.meta.user_id,
+            saved.path.display()
+        ),
+        Ok(saved) => println!("Play history saved to file: {}", saved.path.display()),
+        Err(e) => eprintln!("Error saving play history of {}: {}", history.user_id, e),
+    }
+    saved
+}
+
+/// Saves, backs up and indexes a history unless the user already has a session with the
+/// same content. Received and imported histories both go through here.
+async fn store_play_history(
+    history: &PlayHistory,
+    source: Option<SocketAddr>,
+    received_at: u64,
+    db: &HistoryDb,
+) -> Result<SavedSession, HistoryError> {
+    let config = config::current().await;
+    let saved =
+        history_store::save_session(&config.history_dir, history.clone(), source, received_at)
+            .await?;
+    if saved.duplicate {
+        return Ok(saved);
+    }
+
+    if let Some(backup_dir) = &config.backup_dir {
+        if let Err(e) = history_store::backup_session(backup_dir, &saved, config.backup_keep).await
+        {
+            eprintln!("Error backing up {}: {}", saved.path.display(), e);
+        }
+    }
+    let record = SessionRecord {
+        meta: saved.meta.clone(),
+        history: history.clone(),
+    };
+    if let Err(e) = db.insert(&record, &saved.path).await {
+        eprintln!("Error indexing {}: {}", saved.path.display(), e);
+    }
+    Ok(saved)
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
+    lazy_static::initialize(&config::CONFIG);
+    let config = tauri::async_runtime::block_on(config::current());
+
+    let db = HistoryDb::open(&config.database_path).unwrap_or_else(|e| {
+        eprintln!(
+            "Error opening {}, keeping histories in memory: {}",
+            config.database_path.display(),
+            e
+        );
+        HistoryDb::in_memory().expect("error while creating in-memory database")
+    });
+    let import_db = db.clone();
+    let roster = TraineeRoster(RwLock::new(Roster::load(&config.roster_path)));
+    let scan = IntegrityScan::default();
+    let startup_scan = scan.clone();
+
+    let network = FmNetwork::new(&config.device_registry_path);
+    let store_network = network.clone();
+
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
+        .manage(network)
+        .manage(UiSubscriptions::default())
+        .manage(db)
+        .manage(scan)
+        .manage(roster)
+        .setup(move |app| {
+            let app = app.handle().clone();
+            tauri::async_runtime::spawn(async move {
+                // quarantine first so broken files never reach the index
+                let scan = history_store::scan_integrity(&config.history_dir, true).await;
+                for file in &scan.quarantined {
+                    eprintln!(
+                        "Quarantined {} as {}: {}",
+                        file.path, file.moved_to, file.error
+                    );
+                }
+                *startup_scan.0.lock().await = Some(scan);
+
+                let report = import_db.import_dir(&config.history_dir).await;
+                println!(
+                    "Play histories imported: {} migrated, {} added, {} updated, {} removed, {} failed",
+                    report.migrated,
+                    report.added,
+                    report.updated,
+                    report.removed,
+                    report.failed.len()
+                );
+
+                // only now, the scan deletes temp files a save in progress would still need
+                if let Some(histories) = store_network.take_histories().await {
+                    store_received_histories(histories, &import_db, &app).await;
+                }
+            });
+            Ok(())
+        })
         // .invoke_handler(tauri::generate_handler![])
         .invoke_handler(tauri::generate_handler![
             start_udp,
             stop_udp,
+            restart_udp,
+            watch_client,
+            unwatch_client,
             send_msg,
+            send_command,
+            send_to_targets,
+            send_headset_command,
+            request_history,
+            network_status,
+            get_stream_stats,
+            list_devices,
+            rename_device,
+            forget_device,
+            get_config,
+            set_config,
             query_play_histories,
+            validate_play_histories,
+            list_sessions,
+            get_session,
+            query_sessions,
+            import_play_histories,
+            import_history_files,
+            export_play_histories,
+            get_history_stats,
+            list_trainees,
+            save_trainee,
+            remove_trainee,
+            import_roster,
+            reassign_user_id,
+            get_report_template,
+            set_report_template,
+            generate_report,
+            generate_reports,
+            get_integrity_report,
+            scan_play_histories,
             get_history
         ])
         .run(tauri::generate_context!())
@@ -0,0 +1,193 @@
+use std::{fmt::Display, path::Path};
+
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+/// A trainee's play history as sent by the VR client.
+///
+/// Fields this model doesn't know are kept in `extra` and written back unchanged.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlayHistory {
+    /// Stored as `unknown` when not a string or number, as older controllers did.
+    #[serde(deserialize_with = "lenient_user_id")]
+    pub user_id: String,
+    #[serde(default)]
+    pub mission_datas: Vec<MissionData>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MissionData {
+    pub name: String,
+    pub time: f64,
+    pub complete: bool,
+    pub stg_datas: Vec<StageData>,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StageData {
+    pub stg_name: String,
+    pub score: f64,
+    pub time: f64,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+/// Why a play history file or payload was rejected or is suspicious.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "kind", content = "detail", rename_all = "snake_case")]
+pub enum HistoryError {
+    Io(String),
+    /// Not JSON or not the expected shape, e.g. a missing `userId`.
+    Schema(String),
+    /// Well formed but with values that make no sense, one entry per problem.
+    Invalid(Vec<String>),
+    Database(String),
+    /// Writing a CSV or XLSX report failed.
+    Export(String),
+    /// A roster CSV file could not be read.
+    Roster(String),
+}
+
+impl Display for HistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(reason) => write!(f, "{}", reason),
+            Self::Schema(reason) => write!(f, "schema error, {}", reason),
+            Self::Invalid(problems) => write!(f, "invalid values, {}", problems.join("; ")),
+            Self::Database(reason) => write!(f, "database error, {}", reason),
+            Self::Export(reason) => write!(f, "export error, {}", reason),
+            Self::Roster(reason) => write!(f, "roster error, {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for HistoryError {}
+
+impl From<rusqlite::Error> for HistoryError {
+    fn from(e: rusqlite::Error) -> Self {
+        Self::Database(e.to_string())
+    }
+}
+
+/// The validation result of one file in the history directory.
+#[derive(Serialize, Clone, Debug)]
+pub struct HistoryFileReport {
+    pub path: String,
+    pub user_id: Option<String>,
+    pub error: Option<HistoryError>,
+}
+
+/// User id of histories whose `userId` is neither a string nor a number.
+pub const UNKNOWN_USER_ID: &str = "unknown";
+
+fn lenient_user_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    Ok(match Value::deserialize(deserializer)? {
+        Value::String(user_id) => user_id,
+        Value::Number(user_id) => user_id.to_string(),
+        _ => UNKNOWN_USER_ID.into(),
+    })
+}
+
+impl HistoryFileReport {
+    pub fn new(path: &Path, history: Result<&PlayHistory, &HistoryError>) -> Self {
+        let (user_id, error) = match history {
+            Ok(history) => (Some(history.user_id.clone()), history.validate().err()),
+            Err(e) => (None, Some(e.clone())),
+        };
+        Self {
+            path: path.display().to_string(),
+            user_id,
+            error,
+        }
+    }
+}
+
+impl PlayHistory {
+    pub fn from_json(json: &str) -> Result<Self, HistoryError> {
+        serde_json::from_str(json).map_err(|e| HistoryError::Schema(e.to_string()))
+    }
+
+    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self, HistoryError> {
+        let json = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| HistoryError::Io(e.to_string()))?;
+        Self::from_json(&json)
+    }
+
+    /// Checks the values serde can't, reporting every problem with its location.
+    pub fn validate(&self) -> Result<(), HistoryError> {
+        let mut problems = Vec::new();
+        if self.user_id.trim().is_empty() {
+            problems.push("userId: must not be empty".to_owned());
+        }
+
+        for (i, mission) in self.mission_datas.iter().enumerate() {
+            let at = format!("missionDatas[{}]", i);
+            if mission.name.trim().is_empty() {
+                problems.push(format!("{}.name: must not be empty", at));
+            }
+            check_duration(&mut problems, &at, mission.time);
+
+            for (j, stage) in mission.stg_datas.iter().enumerate() {
+                let at = format!("{}.stgDatas[{}]", at, j);
+                if stage.stg_name.trim().is_empty() {
+                    problems.push(format!("{}.stgName: must not be empty", at));
+                }
+                if !stage.score.is_finite() {
+                    problems.push(format!("{}.score: must be a finite number", at));
+                }
+                check_duration(&mut problems, &at, stage.time);
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(HistoryError::Invalid(problems))
+        }
+    }
+}
+
+fn check_duration(problems: &mut Vec<String>, at: &str, time: f64) {
+    if !(time.is_finite() && time >= 0.0) {
+        problems.push(format!(
+            "{}.time: must be a non-negative number, got {}",
+            at, time
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_histories_without_mission_datas() {
+        let history = PlayHistory::from_json(r#"{"userId":"u1"}"#).unwrap();
+        assert!(history.mission_datas.is_empty());
+        assert!(history.validate().is_ok());
+    }
+
+    #[test]
+    fn accepts_a_non_string_user_id() {
+        let history = PlayHistory::from_json(r#"{"userId":42,"missionDatas":[]}"#).unwrap();
+        assert_eq!(history.user_id, "42");
+
+        let history = PlayHistory::from_json(r#"{"userId":null,"missionDatas":[]}"#).unwrap();
+        assert_eq!(history.user_id, UNKNOWN_USER_ID);
+    }
+
+    #[test]
+    fn keeps_unknown_fields() {
+        let json = r#"{"userId":"u1","missionDatas":[],"device":"quest"}"#;
+        let history = PlayHistory::from_json(json).unwrap();
+        assert_eq!(serde_json::to_string(&history).unwrap(), json);
+    }
+}
@@ -0,0 +1,294 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::history_store;
+use crate::play_history::HistoryError;
+
+/// A trainee, linked to play histories by the `userId` chosen in the headset.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Trainee {
+    pub user_id: String,
+    pub name: String,
+    /// Class or group the trainee belongs to.
+    #[serde(default)]
+    pub group: Option<String>,
+    /// Id in the training organisation's own records.
+    #[serde(default)]
+    pub external_id: Option<String>,
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct RosterImportReport {
+    pub added: usize,
+    pub updated: usize,
+    pub failed: Vec<RosterRowError>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RosterRowError {
+    /// 1-based line in the CSV file, the header being line 1.
+    pub line: u64,
+    pub reason: String,
+}
+
+/// Trainees known to the controller, keyed by user id and persisted as JSON.
+///
+/// Changes are made on a clone that replaces the roster once saved, so a failed save
+/// leaves memory and file in agreement.
+#[derive(Clone)]
+pub struct Roster {
+    path: PathBuf,
+    trainees: HashMap<String, Trainee>,
+}
+
+/// Column indexes of a roster CSV, found by header name.
+struct CsvColumns {
+    user_id: usize,
+    name: usize,
+    group: Option<usize>,
+    external_id: Option<usize>,
+}
+
+impl Trainee {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.user_id.trim().is_empty() {
+            return Err("user id must not be empty".into());
+        }
+        if self.name.trim().is_empty() {
+            return Err("name must not be empty".into());
+        }
+        Ok(())
+    }
+}
+
+impl Roster {
+    /// Reads the roster file, starting empty if it is missing or unreadable.
+    pub fn load<P: AsRef<Path>>(path: P) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let trainees = match std::fs::read_to_string(&path) {
+            Ok(json) => match serde_json::from_str::<Vec<Trainee>>(&json) {
+                Ok(trainees) => trainees
+                    .into_iter()
+                    .map(|trainee| (trainee.user_id.clone(), trainee))
+                    .collect(),
+                Err(e) => {
+                    eprintln!("Error parsing roster {}: {}", path.display(), e);
+                    HashMap::new()
+                }
+            },
+            Err(_) => HashMap::new(),
+        };
+
+        Self { path, trainees }
+    }
+
+    pub async fn save(&self) -> Result<(), HistoryError> {
+        let json = serde_json::to_string_pretty(&self.list(None))
+            .map_err(|e| HistoryError::Schema(e.to_string()))?;
+        history_store::write_atomic(&self.path, json.as_bytes()).await
+    }
+
+    /// Every trainee, or those of `group`, by group and name.
+    pub fn list(&self, group: Option<&str>) -> Vec<Trainee> {
+        let mut trainees: Vec<Trainee> = self
+            .trainees
+            .values()
+            .filter(|trainee| group.is_none() || trainee.group.as_deref() == group)
+            .cloned()
+            .collect();
+        trainees.sort_by(|a, b| {
+            a.group
+                .cmp(&b.group)
+                .then(a.name.cmp(&b.name))
+                .then(a.user_id.cmp(&b.user_id))
+        });
+        trainees
+    }
+
+    pub fn get(&self, user_id: &str) -> Option<&Trainee> {
+        self.trainees.get(user_id)
+    }
+
+    /// The known trainees among `user_ids`.
+    pub fn lookup<'a>(
+        &self,
+        user_ids: impl IntoIterator<Item = &'a str>,
+    ) -> HashMap<String, Trainee> {
+        user_ids
+            .into_iter()
+            .filter_map(|user_id| self.get(user_id))
+            .map(|trainee| (trainee.user_id.clone(), trainee.clone()))
+            .collect()
+    }
+
+    /// Adds or replaces the trainee with the same user id, `true` if it was new.
+    pub fn upsert(&mut self, trainee: Trainee) -> Result<bool, String> {
+        trainee.validate()?;
+        Ok(self
+            .trainees
+            .insert(trainee.user_id.clone(), trainee)
+            .is_none())
+    }
+
+    pub fn remove(&mut self, user_id: &str) -> bool {
+        self.trainees.remove(user_id).is_some()
+    }
+
+    /// Moves the trainee of `from` to `to`, `false` if `from` has none.
+    ///
+    /// Fails if both have one, neither is changed then.
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<bool, String> {
+        if !self.trainees.contains_key(from) {
+            return Ok(false);
+        }
+        if self.trainees.contains_key(to) {
+            return Err(format!("{} and {} both have a roster entry", from, to));
+        }
+
+        let mut trainee = self.trainees.remove(from).expect("trainee exists");
+        trainee.user_id = to.into();
+        self.trainees.insert(to.into(), trainee);
+        Ok(true)
+    }
+
+    /// Adds or updates one trainee per row of a CSV file with a header row naming the
+    /// `user_id`, `name`, `group` and `external_id` columns. Bad rows are reported and skipped.
+    pub fn import_csv(&mut self, data: &[u8]) -> Result<RosterImportReport, HistoryError> {
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(data);
+        let headers = reader
+            .headers()
+            .map_err(|e| HistoryError::Roster(e.to_string()))?;
+        let columns = CsvColumns::find(headers)?;
+
+        let mut report = RosterImportReport::default();
+        for row in reader.records() {
+            let row = match row {
+                Ok(row) => row,
+                Err(e) => {
+                    report.failed.push(RosterRowError {
+                        line: e.position().map(|at| at.line()).unwrap_or_default(),
+                        reason: e.to_string(),
+                    });
+                    continue;
+                }
+            };
+            let line = row.position().map(|at| at.line()).unwrap_or_default();
+            let cell = |at: Option<usize>| {
+                at.and_then(|at| row.get(at))
+                    .filter(|value| !value.is_empty())
+                    .map(str::to_owned)
+            };
+
+            let trainee = Trainee {
+                user_id: cell(Some(columns.user_id)).unwrap_or_default(),
+                name: cell(Some(columns.name)).unwrap_or_default(),
+                group: cell(columns.group),
+                external_id: cell(columns.external_id),
+            };
+            match self.upsert(trainee) {
+                Ok(true) => report.added += 1,
+                Ok(false) => report.updated += 1,
+                Err(reason) => report.failed.push(RosterRowError { line, reason }),
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl CsvColumns {
+    fn find(headers: &csv::StringRecord) -> Result<Self, HistoryError> {
+        // `userId`, `User ID` and `user_id` all name the same column, a BOM is dropped too
+        let normalized: Vec<String> = headers
+            .iter()
+            .map(|header| {
+                header
+                    .chars()
+                    .filter(|c| c.is_alphanumeric())
+                    .flat_map(char::to_lowercase)
+                    .collect()
+            })
+            .collect();
+        let find = |names: &[&str]| {
+            normalized
+                .iter()
+                .position(|header| names.contains(&header.as_str()))
+        };
+
+        let missing = |column: &str| HistoryError::Roster(format!("no {} column", column));
+        Ok(Self {
+            user_id: find(&["userid", "user"]).ok_or_else(|| missing("user_id"))?,
+            name: find(&["name", "trainee"]).ok_or_else(|| missing("name"))?,
+            group: find(&["group", "class"]),
+            external_id: find(&["externalid"]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roster() -> Roster {
+        Roster {
+            path: PathBuf::new(),
+            trainees: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn headers_match_whatever_their_spelling() {
+        let csv = "\u{feff}Class, Trainee ,User ID,External-Id\nA,Ann,u1,x9\n";
+        let mut roster = roster();
+        let report = roster.import_csv(csv.as_bytes()).unwrap();
+
+        assert_eq!(report.added, 1);
+        assert_eq!(
+            roster.get("u1"),
+            Some(&Trainee {
+                user_id: "u1".into(),
+                name: "Ann".into(),
+                group: Some("A".into()),
+                external_id: Some("x9".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn missing_required_column_is_a_roster_error() {
+        let result = roster().import_csv(b"user_id,group\nu1,A\n");
+        assert!(matches!(result, Err(HistoryError::Roster(reason)) if reason == "no name column"));
+    }
+
+    #[test]
+    fn bad_rows_are_reported_by_line() {
+        let csv = "userId,name\nu1,Ann\n,Nobody\nu1,Anna\n";
+        let mut roster = roster();
+        let report = roster.import_csv(csv.as_bytes()).unwrap();
+
+        assert_eq!((report.added, report.updated), (1, 1));
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].line, 3);
+        assert_eq!(roster.get("u1").unwrap().name, "Anna");
+    }
+
+    #[test]
+    fn rename_refuses_to_overwrite_a_trainee() {
+        let mut roster = roster();
+        roster
+            .import_csv(b"user_id,name\nu1,Ann\nu2,Bob\n")
+            .unwrap();
+
+        assert!(roster.rename("u1", "u2").is_err());
+        assert_eq!(roster.get("u1").unwrap().name, "Ann");
+        assert_eq!(roster.rename("u1", "u3"), Ok(true));
+        assert_eq!(roster.get("u3").unwrap().user_id, "u3");
+        assert_eq!(roster.rename("u1", "u4"), Ok(false));
+    }
+}